@@ -1,5 +1,4 @@
 use super::AgentState;
-use crate::block::Block;
 use crate::controls::action_mapping::{ActionState, GameAction};
 use crate::world::RenderDistance;
 use crate::world::{BlockPos, BlockRayCastHit, VoxelWorld};
@@ -172,8 +171,7 @@ pub fn move_player(
         // Part 2: Handle physics (gravity and step-up)
         // Check if player is on ground
         let below_pos = player_pos + Vec3::new(0.0, -1.05, 0.0);
-        let block_below = world.get_block_safe(BlockPos::from(below_pos));
-        let on_ground = block_below != Block::Air;
+        let on_ground = world.is_solid(BlockPos::from(below_pos));
 
         // Handle jumping
         if action_state.just_pressed(GameAction::Jump) && on_ground {
@@ -184,11 +182,8 @@ pub fn move_player(
         if on_ground && (velocity.0.x.abs() + velocity.0.z.abs() > 0.1) {
             let movement_dir = Vec3::new(velocity.0.x, 0.0, velocity.0.z).normalize();
             let step_pos = player_pos + movement_dir * 0.8 + Vec3::new(0.0, 0.5, 0.0);
-            let step_block = world.get_block_safe(BlockPos::from((step_pos)));
-
-            if step_block == Block::Air
-                && world.get_block_safe(BlockPos::from((step_pos + Vec3::new(0.0, -0.5, 0.0))))
-                    != Block::Air
+            if !world.is_solid(BlockPos::from(step_pos))
+                && world.is_solid(BlockPos::from(step_pos + Vec3::new(0.0, -0.5, 0.0)))
             {
                 // Found a step, apply gentle upward velocity
                 velocity.0.y = 5.0;
@@ -26,6 +26,8 @@ pub enum BlockFamily {
     Stone,
     Ore,
     Utility,
+    Soil,
+    Fluid,
 }
 
 #[derive(
@@ -57,6 +59,11 @@ pub enum Block {
     OakLeaves,
     SpruceLeaves,
     BirchLeaves,
+    Dirt,
+    Grass,
+    Sand,
+    Snow,
+    Water,
 }
 
 impl Block {
@@ -70,6 +77,8 @@ impl Block {
             Block::Stone => vec![BlockFamily::Stone],
             Block::IronOre | Block::GoldOre | Block::DepletedIronOre => vec![BlockFamily::Ore],
             Block::Furnace | Block::FurnaceOn => vec![BlockFamily::Utility],
+            Block::Dirt | Block::Grass | Block::Sand | Block::Snow => vec![BlockFamily::Soil],
+            Block::Water => vec![BlockFamily::Fluid],
         }
     }
 
@@ -128,13 +137,20 @@ impl Block {
     }
 
     pub fn is_targetable(&self) -> bool {
-        let untargetable_families = [BlockFamily::Utility, BlockFamily::Empty];
+        let untargetable_families = [BlockFamily::Utility, BlockFamily::Empty, BlockFamily::Fluid];
         !untargetable_families
             .iter()
             .any(|family| self.families().contains(family))
     }
+    /// Whether agents bump into it
+    pub fn is_collidable(&self) -> bool {
+        let not_collidable = [BlockFamily::Empty, BlockFamily::Fluid];
+        !not_collidable
+            .iter()
+            .any(|family| self.families().contains(family))
+    }
     pub fn is_opaque(&self) -> bool {
-        let not_opaque = [BlockFamily::Utility, BlockFamily::Empty];
+        let not_opaque = [BlockFamily::Utility, BlockFamily::Empty, BlockFamily::Fluid];
         !not_opaque
             .iter()
             .any(|family| self.families().contains(family))
@@ -0,0 +1,95 @@
+use crate::{
+    block::Block,
+    utils::math::{ranges, ClosestTrait},
+};
+use std::ops::Range;
+use strum_macros::{Display, EnumIter, EnumString};
+
+/// Biome bounds in (temperature, humidity, altitude) space, all in [0, 1]
+pub type BiomeTable = Vec<([Range<f32>; 3], Biome)>;
+
+#[derive(Debug, Display, EnumString, EnumIter, PartialEq, Eq, Hash, Clone, Copy, Default)]
+pub enum Biome {
+    #[default]
+    Ocean,
+    Beach,
+    Desert,
+    Plains,
+    Forest,
+    BirchForest,
+    Taiga,
+    Tundra,
+    Mountains,
+    SnowyPeaks,
+}
+
+/// Biome of every x,z of a column, indexed with z + x*CHUNK_S1
+pub type ColumnBiomes = Box<[Biome]>;
+
+pub fn load_biome_table() -> BiomeTable {
+    ranges::from_csv_reader(include_str!("../../assets/gen/biomes.csv").as_bytes())
+        .expect("assets/gen/biomes.csv is malformed")
+}
+
+pub fn closest_biome(table: &BiomeTable, temperature: f32, humidity: f32, altitude: f32) -> Biome {
+    *table.closest([temperature, humidity, altitude]).0
+}
+
+impl Biome {
+    /// Top block of the ground
+    pub fn surface(&self) -> Block {
+        match self {
+            Biome::Ocean | Biome::Beach | Biome::Desert => Block::Sand,
+            Biome::Tundra | Biome::SnowyPeaks => Block::Snow,
+            Biome::Mountains => Block::Stone,
+            _ => Block::Grass,
+        }
+    }
+
+    /// Blocks between the surface and the stone
+    pub fn soil(&self) -> (Block, i32) {
+        match self {
+            Biome::Ocean | Biome::Beach => (Block::Sand, 4),
+            Biome::Desert => (Block::Sand, 8),
+            Biome::Mountains => (Block::Stone, 0),
+            Biome::SnowyPeaks => (Block::Stone, 0),
+            Biome::Tundra => (Block::Dirt, 2),
+            _ => (Block::Dirt, 4),
+        }
+    }
+
+    /// Chance for a tree to grow in each tree spot
+    pub fn tree_density(&self) -> f32 {
+        match self {
+            Biome::Forest | Biome::BirchForest => 0.6,
+            Biome::Taiga => 0.5,
+            Biome::Plains => 0.05,
+            Biome::Tundra => 0.02,
+            _ => 0.,
+        }
+    }
+
+    /// Tree species (by their log) with their relative weights
+    pub fn trees(&self) -> &'static [(Block, f32)] {
+        match self {
+            Biome::Forest => &[(Block::OakLog, 0.7), (Block::BirchLog, 0.3)],
+            Biome::BirchForest => &[(Block::BirchLog, 0.85), (Block::OakLog, 0.15)],
+            Biome::Taiga | Biome::Tundra => &[(Block::SpruceLog, 1.)],
+            Biome::Plains => &[(Block::OakLog, 1.)],
+            _ => &[],
+        }
+    }
+
+    /// Multiplies the color of grass and leaves
+    pub fn tint(&self) -> [f32; 3] {
+        match self {
+            Biome::Plains => [1.15, 1.05, 0.8],
+            Biome::Forest => [0.9, 1., 0.9],
+            Biome::BirchForest => [1.1, 1.1, 0.8],
+            Biome::Taiga => [0.7, 0.9, 0.85],
+            Biome::Tundra => [0.8, 0.9, 1.],
+            Biome::Desert | Biome::Beach => [1.3, 1.1, 0.7],
+            _ => [1., 1., 1.],
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use super::{
+    noise::{Fbm, Simplex},
+    pipeline::Run,
+};
+use crate::{
+    block::Block,
+    world::{ColPos, ColedPos, CHUNK_S1, CHUNK_S1I, WATER_H},
+};
+use itertools::iproduct;
+
+// caves are sampled every CAVE_CELL blocks and interpolated in between
+const CAVE_CELL: i32 = 4;
+// solid blocks always kept between a cave and the ground of anything under water
+const ROOF: i32 = 8;
+// how far to look for lower ground (an ocean, a river) before carving under water
+pub const SHORE_MARGIN: usize = 8;
+// the bottom of the world is never carved
+const CAVE_FLOOR: i32 = 4;
+// how close to 0 both spaghetti noises need to be for a tunnel
+const TUNNEL_WIDTH: f32 = 0.07;
+// how close to 0 the ravine noise needs to be for a ravine
+const RAVINE_WIDTH: f32 = 0.015;
+const RAVINE_DEPTH: f32 = 72.;
+
+/// 3D noise cave systems: spaghetti tunnels, cheese caverns and ravines.
+/// Only depends on world coordinates so caves line up across column borders.
+pub struct Caves {
+    // tunnels follow the lines where both noises are close to 0
+    spaghetti: [Fbm<Simplex>; 2],
+    // large caverns where the noise is high, more of them deep down
+    cheese: Fbm<Simplex>,
+    ravines: Fbm,
+    // keeps ravines rare
+    ravine_mask: Fbm,
+}
+
+/// Cave samples of a column on the CAVE_CELL lattice
+#[derive(Clone, Default)]
+pub struct CaveSamples {
+    // lattice coordinates of the first sample
+    origin: (i32, i32),
+    // samples along x, y and z
+    size: (usize, usize, usize),
+    values: Vec<f32>,
+    // lowest ground within SHORE_MARGIN of each x,z of the column, indexed with z + x*CHUNK_S1
+    floor_near: Vec<i32>,
+    // highest water level within SHORE_MARGIN, indexed the same way
+    water_near: Vec<i32>,
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn lattice(start: i32, len: i32) -> std::ops::RangeInclusive<i32> {
+    start.div_euclid(CAVE_CELL)..=(start + len - 1).div_euclid(CAVE_CELL) + 1
+}
+
+/// Lowest ground and highest water level among the cells
+fn nearest(cells: impl Iterator<Item = (i32, i32)>) -> (i32, i32) {
+    cells.fold((i32::MAX, WATER_H), |(floor, water), (ground, level)| {
+        (floor.min(ground), water.max(level))
+    })
+}
+
+impl CaveSamples {
+    fn value(&self, lx: usize, ly: usize, lz: usize) -> f32 {
+        self.values[(lx * self.size.1 + ly) * self.size.2 + lz]
+    }
+
+    /// Trilinear interpolation of the samples at a block
+    fn density(&self, x: i32, y: i32, z: i32) -> f32 {
+        let cell = |v: i32, origin: i32| {
+            (
+                (v.div_euclid(CAVE_CELL) - origin) as usize,
+                v.rem_euclid(CAVE_CELL) as f32 / CAVE_CELL as f32,
+            )
+        };
+        let (lx, fx) = cell(x, self.origin.0);
+        let (ly, fy) = cell(y, 0);
+        let (lz, fz) = cell(z, self.origin.1);
+        let ly = ly.min(self.size.1 - 2);
+        let plane = |lx: usize| {
+            lerp(
+                lerp(self.value(lx, ly, lz), self.value(lx, ly, lz + 1), fz),
+                lerp(
+                    self.value(lx, ly + 1, lz),
+                    self.value(lx, ly + 1, lz + 1),
+                    fz,
+                ),
+                fy,
+            )
+        };
+        lerp(plane(lx), plane(lx + 1), fx)
+    }
+}
+
+impl Caves {
+    pub fn new(seed: i32) -> Self {
+        Caves {
+            spaghetti: [
+                Fbm::with_noise(Simplex::new(seed.wrapping_add(10)), 2, 1. / 64.),
+                Fbm::with_noise(Simplex::new(seed.wrapping_add(11)), 2, 1. / 64.),
+            ],
+            cheese: Fbm::with_noise(Simplex::new(seed.wrapping_add(12)), 2, 1. / 96.),
+            ravines: Fbm::new(seed.wrapping_add(13), 2, 1. / 384.),
+            ravine_mask: Fbm::new(seed.wrapping_add(14), 1, 1. / 512.),
+        }
+    }
+
+    /// Positive where a cave is
+    fn density(&self, x: f32, y: f32, z: f32) -> f32 {
+        // squashing y makes tunnels and caverns wider than they are tall
+        let a = self.spaghetti[0].get3(x, y * 2., z);
+        let b = self.spaghetti[1].get3(x, y * 2., z);
+        let tunnel = (TUNNEL_WIDTH - a.abs().max(b.abs())) * 4.;
+        let depth = (y / WATER_H as f32).clamp(0., 1.);
+        let cavern = self.cheese.get3(x, y * 1.5, z) - 0.45 - 0.3 * depth;
+        tunnel.max(cavern)
+    }
+
+    /// How deep a ravine cuts down from the ground at x,z, 0 if there's none
+    fn ravine_depth(&self, x: i32, z: i32) -> i32 {
+        let (x, z) = (x as f32, z as f32);
+        let dist = self.ravines.get2(x, z).abs();
+        if dist >= RAVINE_WIDTH || self.ravine_mask.get2(x, z) < 0.25 {
+            return 0;
+        }
+        (RAVINE_DEPTH * (1. - dist / RAVINE_WIDTH).sqrt()) as i32
+    }
+
+    /// Samples the caves of a column up to `max_y`, `ground` gives the ground and the
+    /// water level at x,z counted from SHORE_MARGIN blocks before the column,
+    /// up to SHORE_MARGIN blocks past it
+    pub fn sample(
+        &self,
+        col_pos: ColPos,
+        max_y: i32,
+        ground: impl Fn(usize, usize) -> (i32, i32),
+    ) -> CaveSamples {
+        let (x0, z0) = (col_pos.x * CHUNK_S1I, col_pos.z * CHUNK_S1I);
+        let (xs, zs) = (lattice(x0, CHUNK_S1I), lattice(z0, CHUNK_S1I));
+        let ys = 0..=max_y.max(0).div_euclid(CAVE_CELL) + 1;
+        let values = iproduct!(xs.clone(), ys.clone(), zs.clone())
+            .map(|(lx, ly, lz)| {
+                let c = CAVE_CELL as f32;
+                self.density(lx as f32 * c, ly as f32 * c, lz as f32 * c)
+            })
+            .collect();
+
+        // within SHORE_MARGIN along z, then along x
+        let along_z: Vec<(i32, i32)> = iproduct!(0..CHUNK_S1 + 2 * SHORE_MARGIN, 0..CHUNK_S1)
+            .map(|(x, z)| nearest((z..=z + 2 * SHORE_MARGIN).map(|z| ground(x, z))))
+            .collect();
+        let (floor_near, water_near) = iproduct!(0..CHUNK_S1, 0..CHUNK_S1)
+            .map(|(x, z)| nearest((x..=x + 2 * SHORE_MARGIN).map(|x| along_z[z + x * CHUNK_S1])))
+            .unzip();
+        CaveSamples {
+            origin: (*xs.start(), *zs.start()),
+            size: (xs.count(), ys.count(), zs.count()),
+            values,
+            floor_near,
+            water_near,
+        }
+    }
+
+    /// Air runs carved in the row x of the column. Under the water level of the sea
+    /// or of a river nearby, caves stay ROOF blocks below any ground nearby so that
+    /// water never flows in.
+    pub fn carve_row(
+        &self,
+        samples: &CaveSamples,
+        col_pos: ColPos,
+        x: usize,
+        ground: impl Fn(ColedPos) -> i32,
+        runs: &mut Vec<Run>,
+    ) {
+        let (x0, z0) = (col_pos.x * CHUNK_S1I, col_pos.z * CHUNK_S1I);
+        let wx = x0 + x as i32;
+        for z in 0..CHUNK_S1 {
+            let wz = z0 + z as i32;
+            let top = ground((x, z));
+            let floor_near = samples.floor_near[z + x * CHUNK_S1];
+            let water_near = samples.water_near[z + x * CHUNK_S1];
+            let ravine_bottom = top - self.ravine_depth(wx, wz);
+            let mut run_top = None;
+            for y in (CAVE_FLOOR..=top).rev() {
+                let dry = y > water_near || y < floor_near - ROOF;
+                let carved = dry && (y > ravine_bottom || samples.density(wx, y, wz) > 0.);
+                match (carved, run_top) {
+                    (true, None) => run_top = Some(y),
+                    (false, Some(run)) => {
+                        runs.push(((x, z), y + 1, run, Block::Air));
+                        run_top = None;
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(run) = run_top {
+                runs.push(((x, z), CAVE_FLOOR, run, Block::Air));
+            }
+        }
+    }
+}
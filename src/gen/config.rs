@@ -0,0 +1,154 @@
+use super::{
+    biomes::{load_biome_table, BiomeTable},
+    ores::{load_ore_table, ores_from_csv_reader, OreTable},
+};
+use crate::utils::math::ranges;
+use anyhow::{Context, Result};
+use bevy::{
+    asset::{
+        io::{file::FileAssetReader, Reader},
+        AssetLoader, LoadContext,
+    },
+    prelude::*,
+};
+use serde::Deserialize;
+use std::path::PathBuf;
+
+pub const GEN_CONFIG_PATH: &str = "gen/earth.json5";
+
+/// Where an asset is on disk, from the same root as the asset server
+/// (`BEVY_ASSET_ROOT`, else the manifest folder, else the executable's)
+pub fn asset_path(path: &str) -> PathBuf {
+    FileAssetReader::get_base_path().join("assets").join(path)
+}
+
+/// Parameters of the Earth generator, as written in `assets/gen/earth.json5`.
+/// Missing keys keep their default.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct EarthSettings {
+    pub continents_freq: f32,
+    pub erosion_freq: f32,
+    pub peaks_freq: f32,
+    pub climate_freq: f32,
+    pub erosion_quality: f32,
+    pub soil_depth: f32,
+    pub ore_rate: f32,
+    // paths of the tables, relative to the assets folder
+    pub biomes: String,
+    pub ores: String,
+}
+
+impl EarthSettings {
+    pub fn parse(bytes: &[u8]) -> Result<EarthSettings> {
+        Ok(json5::from_str(std::str::from_utf8(bytes)?)?)
+    }
+}
+
+impl Default for EarthSettings {
+    fn default() -> Self {
+        EarthSettings {
+            continents_freq: 1. / 2048.,
+            erosion_freq: 1. / 1024.,
+            peaks_freq: 1. / 512.,
+            climate_freq: 1. / 4096.,
+            erosion_quality: 1.,
+            soil_depth: 1.,
+            ore_rate: 1.,
+            biomes: "gen/biomes.csv".to_string(),
+            ores: "gen/ores.csv".to_string(),
+        }
+    }
+}
+
+/// Everything the generators are built from, the settings along with the tables they point to
+#[derive(Asset, TypePath, Clone, Debug, PartialEq)]
+pub struct GenConfig {
+    pub settings: EarthSettings,
+    pub biomes: BiomeTable,
+    pub ores: OreTable,
+}
+
+impl Default for GenConfig {
+    /// The config shipped with the game, available before the asset is loaded
+    fn default() -> Self {
+        GenConfig {
+            settings: json5::from_str(include_str!("../../assets/gen/earth.json5"))
+                .expect("assets/gen/earth.json5 is malformed"),
+            biomes: load_biome_table(),
+            ores: load_ore_table(),
+        }
+    }
+}
+
+impl GenConfig {
+    /// Builds the config from its settings and the bytes of the tables they point to.
+    /// Shared by the asset loader and `GenConfig::from_disk`.
+    pub fn with_tables(settings: EarthSettings, biomes: &[u8], ores: &[u8]) -> Result<GenConfig> {
+        Ok(GenConfig {
+            settings,
+            biomes: ranges::from_csv_reader(biomes)?,
+            ores: ores_from_csv_reader(ores)?,
+        })
+    }
+
+    /// Reads the config from the assets folder, for the tools that run without the asset server
+    pub fn from_disk() -> Result<GenConfig> {
+        let read = |path: &str| {
+            let path = asset_path(path);
+            std::fs::read(&path).with_context(|| format!("Can't read {:?}", path))
+        };
+        let settings = EarthSettings::parse(&read(GEN_CONFIG_PATH)?)?;
+        let (biomes, ores) = (read(&settings.biomes)?, read(&settings.ores)?);
+        GenConfig::with_tables(settings, &biomes, &ores)
+    }
+}
+
+#[derive(Default, TypePath)]
+pub struct GenConfigLoader;
+
+impl AssetLoader for GenConfigLoader {
+    type Asset = GenConfig;
+    type Settings = ();
+    type Error = anyhow::Error;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<GenConfig, Self::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let settings = EarthSettings::parse(&bytes)?;
+        // reading the tables through the context reloads the config when they change too
+        let biomes = load_context
+            .read_asset_bytes(settings.biomes.as_str())
+            .await?;
+        let ores = load_context
+            .read_asset_bytes(settings.ores.as_str())
+            .await?;
+        GenConfig::with_tables(settings, &biomes, &ores)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["json5"]
+    }
+}
+
+/// The config the current generator was built with, and the asset that may replace it
+#[derive(Resource)]
+pub struct ActiveGenConfig {
+    pub handle: Handle<GenConfig>,
+    pub config: GenConfig,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disk_config_is_the_shipped_one() {
+        assert_eq!(GenConfig::from_disk().unwrap(), GenConfig::default());
+    }
+}
@@ -1,17 +1,71 @@
 use crate::block::Block;
-use crate::r#gen::terrain_gen::GenerationPhase;
 use crate::world::{
-    BlockPos, ChunkPos, ColPos, VoxelWorld, CHUNK_S1, CHUNK_S1I, MAX_GEN_HEIGHT, WATER_H,
+    BlockPos2d, ColPos, ColedPos, VoxelWorld, CHUNK_S1, CHUNK_S1I, MAX_GEN_HEIGHT, WATER_H,
 };
-use std::{collections::HashMap, ops::RangeInclusive};
+use bevy::math::Vec2;
+use std::ops::RangeInclusive;
 
-use super::terrain_gen::GenerationState;
+use super::{
+    biomes::{closest_biome, Biome, BiomeTable},
+    caves::{Caves, SHORE_MARGIN},
+    config::GenConfig,
+    erosion::Erosion,
+    generator::{mark_loaded, WorldGenerator},
+    noise::{DomainWarp, Fbm},
+    ores::{ore_runs, OreTable},
+    pipeline::{GenStage, SpillLayer},
+    rivers::{RiverCell, Rivers},
+    terrain_gen::GenerationState,
+    trees::{tree_roots, tree_spills, TREE_SPILL_RADIUS},
+};
 
 pub const CONT_R: f32 = (WATER_H + 2) as f32 / MAX_GEN_HEIGHT as f32;
 pub const CONT_COMPL: f32 = 1. - CONT_R;
 
+// continentalness below which the ground is under water
+const SHORE: f32 = 0.4;
+// height difference with a neighbour above which the ground is bare stone
+const CLIFF_SLOPE: i32 = 4;
+
+// the ground is computed past the column, for the slopes on its border and the caves near the shore
+const HEIGHTS_B: usize = SHORE_MARGIN;
+const HEIGHTS_S1: usize = CHUNK_S1 + 2 * HEIGHTS_B;
+
+/// First block of the ground of the column, border included (see `Earth::heights`)
+fn ground_origin(col_pos: ColPos) -> BlockPos2d {
+    BlockPos2d {
+        x: col_pos.x * CHUNK_S1I - HEIGHTS_B as i32,
+        z: col_pos.z * CHUNK_S1I - HEIGHTS_B as i32,
+    }
+}
+
+/// Index of x,z of the column in its ground (see `Earth::heights`)
+fn ground_index(x: usize, z: usize) -> usize {
+    z + HEIGHTS_B + (x + HEIGHTS_B) * HEIGHTS_S1
+}
+
 pub struct Earth {
-    config: HashMap<String, f32>,
+    seed: i32,
+    // multiplies the soil depth of the biomes
+    soil_depth: f32,
+    // where land and oceans are
+    continents: Fbm,
+    // makes coastlines less round
+    coast_warp: DomainWarp,
+    // flattens the terrain where high
+    erosion: Fbm,
+    // ridges that become mountain ranges inland
+    peaks: Fbm,
+    temperature: Fbm,
+    humidity: Fbm,
+    biomes: BiomeTable,
+    ores: OreTable,
+    caves: Caves,
+    rivers: Rivers,
+    // carves valleys and slopes into the terrain once it's shaped
+    eroder: Erosion,
+    // replaces the climate biomes if set
+    fixed_biome: Option<Biome>,
 }
 
 fn pos_to_range(pos: ColPos) -> [RangeInclusive<i32>; 2] {
@@ -20,121 +74,334 @@ fn pos_to_range(pos: ColPos) -> [RangeInclusive<i32>; 2] {
     [x..=(x + CHUNK_S1I - 1), y..=(y + CHUNK_S1I - 1)]
 }
 
+fn smoothstep(t: f32) -> f32 {
+    t * t * (3. - 2. * t)
+}
+
+/// Combines the raw noise values into a height (in blocks)
+fn shape(continents: f32, erosion: f32, peaks: f32) -> i32 {
+    let cont = (continents * 0.7 + 0.5).clamp(0., 1.);
+    let erosion = (erosion * 0.5 + 0.5).clamp(0., 1.);
+    let peaks = 1. - peaks.abs();
+    let h = if cont < SHORE {
+        // the sea floor rises towards the coast but stays under water
+        CONT_R * (0.4 + 0.55 * cont / SHORE)
+    } else {
+        let inland = (cont - SHORE) / (1. - SHORE);
+        let plains = 0.06 * inland;
+        let mountains = peaks.powi(3) * (1. - erosion).powi(2) * smoothstep(inland);
+        CONT_R + CONT_COMPL * (plains + 0.9 * mountains)
+    };
+    (h * MAX_GEN_HEIGHT as f32) as i32
+}
+
 impl Earth {
-    pub fn new(config: HashMap<String, f32>) -> Self {
-        Earth { config }
+    pub fn new(seed: i32, config: &GenConfig) -> Self {
+        let settings = &config.settings;
+        let continents = Fbm::new(seed, 5, settings.continents_freq);
+        let coast_warp = DomainWarp::new(seed.wrapping_add(3), 3, 1. / 512., 96.);
+        let erosion = Fbm::new(seed.wrapping_add(1), 3, settings.erosion_freq);
+        let peaks = Fbm::new(seed.wrapping_add(2), 4, settings.peaks_freq);
+        let temperature = Fbm::new(seed.wrapping_add(4), 3, settings.climate_freq);
+        let humidity = Fbm::new(seed.wrapping_add(5), 3, settings.climate_freq);
+        let eroder = Erosion::new(seed, settings.erosion_quality);
+        let mut ores = config.ores.clone();
+        for rule in ores.iter_mut() {
+            rule.veins *= settings.ore_rate;
+        }
+        Earth {
+            seed,
+            soil_depth: settings.soil_depth.max(0.),
+            continents,
+            coast_warp,
+            erosion,
+            peaks,
+            temperature,
+            humidity,
+            biomes: config.biomes.clone(),
+            ores,
+            caves: Caves::new(seed),
+            rivers: Rivers::new(seed),
+            eroder,
+            fixed_biome: None,
+        }
     }
-    pub fn process_generation_chunk(
-        &self,
-        state: &mut GenerationState,
-        world: &VoxelWorld,
-        max_time_ms: u32,
-        start_time: std::time::Instant,
-    ) -> bool {
-        const HILL_SCALE_X: f32 = 0.00;
-        const HILL_SCALE_Z: f32 = 0.00;
-
-        loop {
-            match state.phase {
-                GenerationPhase::CalculatingHeights => {
-                    // Process a batch of height calculations
-                    while state.current_x < CHUNK_S1 {
-                        while state.current_z < CHUNK_S1 {
-                            // Calculate absolute block positions
-                            let abs_x = state.col_pos.x * CHUNK_S1I + state.current_x as i32;
-                            let abs_z = state.col_pos.z * CHUNK_S1I + state.current_z as i32;
-
-                            // Generate rolling hills using sine waves
-                            let x_factor = (abs_x as f32 * HILL_SCALE_X).sin();
-                            let z_factor = (abs_z as f32 * HILL_SCALE_Z).sin();
-                            let diagonal_factor =
-                                ((abs_x as f32 + abs_z as f32) * HILL_SCALE_X * 0.7).sin();
-
-                            // Combine waves for more natural looking hills
-                            let height_factor = (x_factor + z_factor + diagonal_factor) / 3.0;
-
-                            // Calculate final height (base + hills)
-                            let top_height = state.base_height
-                                + (height_factor * state.hill_height as f32) as i32;
-                            state.top_height = Some(top_height);
-
-                            // Fill with dirt from y=0 to top_height-1
-                            for y in 0..top_height {
-                                let pos = BlockPos {
-                                    x: abs_x,
-                                    y,
-                                    z: abs_z,
-                                };
-                                world.set_block(pos, Block::OakLog, false);
-                            }
 
-                            // Add grass at the top
-                            let grass_pos = BlockPos {
-                                x: abs_x,
-                                y: top_height,
-                                z: abs_z,
-                            };
-                            world.set_block(grass_pos, Block::OakLog, false);
+    /// The same terrain but with a single biome everywhere
+    pub fn with_biome(mut self, biome: Biome) -> Self {
+        self.fixed_biome = Some(biome);
+        self
+    }
 
-                            // Move to the next position
-                            state.current_z += 1;
+    /// Height of the terrain at x,z (in blocks), straight from the noise.
+    /// Rivers are traced on it so that they don't depend on erosion.
+    fn terrain_height(&self, x: i32, z: i32) -> i32 {
+        self.terrain_grid(BlockPos2d { x, z }, 1)[0]
+    }
 
-                            // Check if we've spent too much time
-                            if start_time.elapsed().as_millis() > max_time_ms as u128 {
-                                return false; // Not completed yet
-                            }
-                        }
-                        state.current_z = 0;
-                        state.current_x += 1;
+    /// Terrain heights of a square region, indexed with z + x*size, batched with SIMD
+    fn terrain_grid(&self, origin: BlockPos2d, size: usize) -> Vec<i32> {
+        let continents = self.continents.grid2(origin, size, Some(&self.coast_warp));
+        let erosion = self.erosion.grid2(origin, size, None);
+        let peaks = self.peaks.grid2(origin, size, None);
+        (0..size * size)
+            .map(|i| shape(continents[i], erosion[i], peaks[i]))
+            .collect()
+    }
 
-                        // Check time again after completing a row
-                        if start_time.elapsed().as_millis() > max_time_ms as u128 {
-                            return false; // Not completed yet
-                        }
-                    }
+    /// Eroded heights of a square region, indexed with z + x*size
+    fn eroded_grid(&self, origin: BlockPos2d, size: usize) -> Vec<i32> {
+        if !self.eroder.is_enabled() {
+            return self.terrain_grid(origin, size);
+        }
+        self.eroder.grid(origin, size, |origin, size| {
+            self.terrain_grid(origin, size)
+                .into_iter()
+                .map(|height| height as f32)
+                .collect()
+        })
+    }
 
-                    // Move to marking chunks
-                    state.phase = GenerationPhase::MarkingChunks;
-                    state.current_x = 0; // Reuse for chunk y index
+    /// Ground and water at x,z once rivers are carved in the terrain
+    pub fn ground(&self, x: i32, z: i32) -> RiverCell {
+        self.ground_grid(BlockPos2d { x, z }, 1)[0]
+    }
 
-                    // Check if we should continue to the next phase in this frame
-                    if start_time.elapsed().as_millis() > max_time_ms as u128 {
-                        return false; // Not completed yet
-                    }
+    /// Ground of a square region once rivers are carved in it, indexed with z + x*size.
+    /// Everything that reads the ground goes through here so that it agrees with the columns.
+    fn ground_grid(&self, origin: BlockPos2d, size: usize) -> Vec<RiverCell> {
+        let heights = self.eroded_grid(origin, size);
+        let min = Vec2::new(origin.x as f32, origin.z as f32);
+        let segments = self.rivers.segments(min, min + (size - 1) as f32, |x, z| {
+            self.terrain_height(x, z)
+        });
+        (0..size * size)
+            .map(|i| {
+                let (x, z) = ((i / size) as i32, (i % size) as i32);
+                self.rivers
+                    .shape(&segments, origin.x + x, origin.z + z, heights[i])
+            })
+            .collect()
+    }
+
+    /// Ground of a whole column and HEIGHTS_B blocks around it, indexed with `ground_index`
+    fn heights(&self, col_pos: ColPos) -> Vec<RiverCell> {
+        self.ground_grid(ground_origin(col_pos), HEIGHTS_S1)
+    }
+
+    /// Biome at x,z given the ground there (see `Earth::ground`)
+    pub fn biome(&self, x: i32, z: i32, cell: &RiverCell) -> Biome {
+        self.biome_grid(BlockPos2d { x, z }, 1, std::slice::from_ref(cell), 1)[0]
+    }
+
+    /// Biomes of a square region, indexed with z + x*size,
+    /// given its ground indexed with z + x*stride (see `Earth::ground_grid`)
+    fn biome_grid(
+        &self,
+        origin: BlockPos2d,
+        size: usize,
+        cells: &[RiverCell],
+        stride: usize,
+    ) -> Vec<Biome> {
+        let temperature = self.temperature.grid2(origin, size, None);
+        let humidity = self.humidity.grid2(origin, size, None);
+        (0..size * size)
+            .map(|i| {
+                let (x, z) = (i / size, i % size);
+                self.climate_biome(temperature[i], humidity[i], &cells[z + x * stride])
+            })
+            .collect()
+    }
+
+    fn climate_biome(&self, temperature: f32, humidity: f32, cell: &RiverCell) -> Biome {
+        if let Some(biome) = self.fixed_biome {
+            return biome;
+        }
+        let altitude = (cell.ground as f32 / MAX_GEN_HEIGHT as f32).clamp(0., 1.);
+        // it gets colder higher up
+        let temperature = (temperature * 0.7 + 0.5 - altitude * 0.3).clamp(0., 1.);
+        // and wetter close to rivers
+        let humidity = (humidity * 0.7 + 0.5 + cell.wetness * 0.3).clamp(0., 1.);
+        closest_biome(&self.biomes, temperature, humidity, altitude)
+    }
 
-                    // Continue to the next phase in this frame
-                    continue;
+    /// Biomes of a whole column, from its ground (see `Earth::heights`)
+    fn col_biomes(&self, col_pos: ColPos, cells: &[RiverCell]) -> Vec<Biome> {
+        let origin = BlockPos2d {
+            x: col_pos.x * CHUNK_S1I,
+            z: col_pos.z * CHUNK_S1I,
+        };
+        self.biome_grid(origin, CHUNK_S1, &cells[ground_index(0, 0)..], HEIGHTS_S1)
+    }
+
+    /// Surface block, soil block and soil depth at x,z of the column
+    fn layers(&self, state: &GenerationState, x: usize, z: usize) -> (Block, Block, i32) {
+        let height_at = |x: usize, z: usize| state.heights[ground_index(x, z)];
+        let top = height_at(x, z);
+        let slope = (height_at(x + 1, z) - top)
+            .abs()
+            .max((height_at(x, z + 1) - top).abs());
+        if state.water[ground_index(x, z)] > top.max(WATER_H) {
+            // river bed
+            return (Block::Sand, Block::Sand, 3);
+        }
+        let biome = state.biomes[z + x * CHUNK_S1];
+        match biome.surface() {
+            // too steep for anything to grow
+            Block::Grass if slope >= CLIFF_SLOPE => (Block::Stone, Block::Stone, 0),
+            surface => {
+                let (soil, soil_depth) = biome.soil();
+                let soil_depth = (soil_depth as f32 * self.soil_depth).round() as i32;
+                (surface, soil, soil_depth)
+            }
+        }
+    }
+
+    /// Highest stone block at x,z of the column
+    fn stone_top(&self, state: &GenerationState, (x, z): ColedPos) -> i32 {
+        let top = state.heights[ground_index(x, z)];
+        match self.layers(state, x, z) {
+            (Block::Stone, _, _) => top,
+            (_, _, soil_depth) => top - 1 - soil_depth,
+        }
+    }
+
+    /// Fills the blocks of a row of x, returns the highest one
+    fn fill_row(&self, world: &VoxelWorld, state: &GenerationState, x: usize) -> i32 {
+        let mut runs = Vec::with_capacity(CHUNK_S1 * 4);
+        let mut highest = WATER_H;
+        for z in 0..CHUNK_S1 {
+            let top = state.heights[ground_index(x, z)];
+            let water = state.water[ground_index(x, z)];
+            let (surface, soil, soil_depth) = self.layers(state, x, z);
+            runs.push(((x, z), 0, top - 1 - soil_depth, Block::Stone));
+            runs.push(((x, z), top - soil_depth, top - 1, soil));
+            runs.push(((x, z), top, top, surface));
+            runs.push(((x, z), top + 1, water, Block::Water));
+            highest = highest.max(top).max(water);
+        }
+        // empty runs (bottom > top) are skipped
+        world.set_col_runs(state.col_pos, &runs);
+        highest
+    }
+
+    /// Applies what the column and its neighbours spilled in it, in a fixed order
+    fn apply_spills(&self, world: &VoxelWorld, state: &mut GenerationState) {
+        let heights = &state.heights;
+        let mut runs = Vec::new();
+        for (layer, spilled) in state.spilled.drain(..) {
+            for ((x, z), bottom, top, block) in spilled {
+                let bottom = match layer {
+                    SpillLayer::Canopy => bottom.max(heights[ground_index(x, z)] + 1),
+                    SpillLayer::Solid => bottom,
+                };
+                if bottom <= top {
+                    runs.push(((x, z), bottom, top, block));
                 }
+            }
+        }
+        if let Some(top) = runs.iter().map(|(_, _, top, _)| *top).max() {
+            state.top_height = Some(state.top_height.unwrap_or(0).max(top));
+        }
+        world.set_col_runs(state.col_pos, &runs);
+    }
+}
 
-                GenerationPhase::MarkingChunks => {
-                    let max_chunk_height =
-                        (state.base_height + state.hill_height + 10) / CHUNK_S1 as i32;
+impl WorldGenerator for Earth {
+    fn seed(&self) -> i32 {
+        self.seed
+    }
 
-                    while state.current_x < max_chunk_height as usize {
-                        let chunk_pos = ChunkPos {
-                            x: state.col_pos.x,
-                            y: state.current_x as i32,
-                            z: state.col_pos.z,
-                        };
+    fn spill_radius(&self) -> i32 {
+        TREE_SPILL_RADIUS
+    }
 
-                        let loaded = world.set_loaded(chunk_pos);
+    fn trim_caches(&self, needed: &[ColPos]) {
+        // the ground each column reads, border included
+        let areas: Vec<BlockPos2d> = needed
+            .iter()
+            .map(|col_pos| ground_origin(*col_pos))
+            .collect();
+        self.eroder
+            .retain(areas.iter().map(|origin| (*origin, HEIGHTS_S1)));
+        self.rivers.retain(areas.iter().map(|origin| {
+            let min = Vec2::new(origin.x as f32, origin.z as f32);
+            (min, min + (HEIGHTS_S1 - 1) as f32)
+        }));
+    }
 
-                        state.current_x += 1;
+    fn process_stage(&self, state: &mut GenerationState, stage: GenStage, world: &VoxelWorld) {
+        match stage {
+            GenStage::Terrain => {
+                let cells = self.heights(state.col_pos);
+                state.heights = cells.iter().map(|cell| cell.ground).collect();
+                state.water = cells.iter().map(|cell| cell.water).collect();
+                state.biomes = self.col_biomes(state.col_pos, &cells);
+                world
+                    .biomes
+                    .insert(state.col_pos, state.biomes.clone().into_boxed_slice());
+                // Fill the column one row at a time
+                for x in 0..CHUNK_S1 {
+                    let top = self.fill_row(world, state, x);
+                    // Keep the highest block to know which chunks to mark
+                    state.top_height = Some(state.top_height.unwrap_or(0).max(top));
+                }
+                let ores = ore_runs(self, &self.ores, state.col_pos, |pos| {
+                    self.stone_top(state, pos)
+                });
+                world.set_col_runs(state.col_pos, &ores);
+            }
 
-                        // Check if we've spent too much time
-                        if start_time.elapsed().as_millis() > max_time_ms as u128 {
-                            return false; // Not completed yet
+            GenStage::Carving => {
+                let col_pos = state.col_pos;
+                // the border of the ground is as wide as the margin the caves look at
+                let samples = self
+                    .caves
+                    .sample(col_pos, state.top_height.unwrap_or(0), |x, z| {
+                        let i = z + x * HEIGHTS_S1;
+                        (state.heights[i], state.water[i])
+                    });
+                for x in 0..CHUNK_S1 {
+                    let mut runs = Vec::new();
+                    self.caves.carve_row(
+                        &samples,
+                        col_pos,
+                        x,
+                        |(x, z)| state.heights[ground_index(x, z)],
+                        &mut runs,
+                    );
+                    world.set_col_runs(col_pos, &runs);
+                    // lower the ground where caves and ravines open up to the surface
+                    for &((x, z), bottom, top, _) in runs.iter() {
+                        let ground = &mut state.heights[ground_index(x, z)];
+                        if top == *ground {
+                            *ground = bottom - 1;
+                            if let Some(mut heights) = world.heights.get_mut(&col_pos) {
+                                heights.set((x, z), Some(bottom - 1), Some(bottom - 1));
+                            }
                         }
                     }
-
-                    // All done!
-                    state.phase = GenerationPhase::Completed;
-                    return true;
                 }
+            }
 
-                GenerationPhase::Completed => {
-                    return true; // Already completed
+            GenStage::Features => {
+                // a finalized column spilling again doesn't have its ground anymore, only its trees
+                if !state.heights.is_empty() {
+                    let i = |(x, z): ColedPos| ground_index(x, z);
+                    state.trees = tree_roots(self, state.col_pos, |pos2d| {
+                        (
+                            state.heights[i(pos2d)],
+                            state.water[i(pos2d)],
+                            state.biomes[pos2d.1 + pos2d.0 * CHUNK_S1],
+                        )
+                    });
                 }
+                state.spills = tree_spills(state.col_pos, &state.trees);
+            }
+
+            GenStage::Finalize => {
+                self.apply_spills(world, state);
+                mark_loaded(state, world);
             }
         }
     }
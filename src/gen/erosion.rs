@@ -0,0 +1,293 @@
+use crate::world::{BlockPos2d, WATER_H};
+use bevy::math::Vec2;
+use dashmap::DashMap;
+use itertools::iproduct;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+// tiles are centered every TILE_SPACING blocks and each covers twice that,
+// so every block is in 4 tiles that are blended together
+const TILE_SPACING: i32 = 64;
+// extra blocks eroded around a tile, where droplets can come from or leave to
+const TILE_PADDING: i32 = 16;
+const TILE_S1: usize = (2 * (TILE_SPACING + TILE_PADDING)) as usize;
+const EROSION_SALT: i32 = 0xE505;
+
+// droplets per block of a tile at quality 1
+const DROPLET_DENSITY: f32 = 0.12;
+const DROPLET_LIFETIME: usize = 32;
+// how much a droplet keeps its direction instead of following the slope
+const INERTIA: f32 = 0.05;
+const CAPACITY: f32 = 4.;
+const MIN_CAPACITY: f32 = 0.01;
+const ERODE_RATE: f32 = 0.3;
+const DEPOSIT_RATE: f32 = 0.3;
+const EVAPORATION: f32 = 0.02;
+const GRAVITY: f32 = 4.;
+// droplets erode the ground within this radius, in blocks
+const BRUSH_RADIUS: i32 = 3;
+
+// thermal steps at quality 1
+const THERMAL_STEPS: f32 = 8.;
+// steepest slope loose ground holds, in blocks per block
+const TALUS: f32 = 1.5;
+const THERMAL_RATE: f32 = 0.4;
+
+/// Heights of a square region, indexed with z + x*size
+struct Tile {
+    origin: BlockPos2d,
+    heights: Vec<f32>,
+}
+
+impl Tile {
+    fn get(&self, x: i32, z: i32) -> f32 {
+        let (x, z) = ((x - self.origin.x) as usize, (z - self.origin.z) as usize);
+        self.heights[z + x * TILE_S1]
+    }
+}
+
+fn unit(rng: &mut u64) -> f32 {
+    // xorshift
+    *rng ^= *rng << 13;
+    *rng ^= *rng >> 7;
+    *rng ^= *rng << 17;
+    (*rng >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// Interpolated height and gradient at a position of the tile
+fn sample(heights: &[f32], pos: Vec2) -> (f32, Vec2) {
+    let (x, z) = (pos.x as usize, pos.y as usize);
+    let (fx, fz) = (pos.x.fract(), pos.y.fract());
+    let at = |x: usize, z: usize| heights[z + x * TILE_S1];
+    let (h00, h01, h10, h11) = (at(x, z), at(x, z + 1), at(x + 1, z), at(x + 1, z + 1));
+    let gradient = Vec2::new(
+        (h10 - h00) * (1. - fz) + (h11 - h01) * fz,
+        (h01 - h00) * (1. - fx) + (h11 - h10) * fx,
+    );
+    let height =
+        h00 * (1. - fx) * (1. - fz) + h10 * fx * (1. - fz) + h01 * (1. - fx) * fz + h11 * fx * fz;
+    (height, gradient)
+}
+
+fn deposit(heights: &mut [f32], pos: Vec2, amount: f32) {
+    let (x, z) = (pos.x as usize, pos.y as usize);
+    let (fx, fz) = (pos.x.fract(), pos.y.fract());
+    heights[z + x * TILE_S1] += amount * (1. - fx) * (1. - fz);
+    heights[z + (x + 1) * TILE_S1] += amount * fx * (1. - fz);
+    heights[z + 1 + x * TILE_S1] += amount * (1. - fx) * fz;
+    heights[z + 1 + (x + 1) * TILE_S1] += amount * fx * fz;
+}
+
+/// Takes `amount` from the ground around pos, more at the center
+fn erode(heights: &mut [f32], pos: Vec2, amount: f32) {
+    let (x, z) = (pos.x as i32, pos.y as i32);
+    let cells: Vec<(usize, f32)> =
+        iproduct!(-BRUSH_RADIUS..=BRUSH_RADIUS, -BRUSH_RADIUS..=BRUSH_RADIUS)
+            .filter_map(|(dx, dz)| {
+                let (cx, cz) = (x + dx, z + dz);
+                if !(0..TILE_S1 as i32).contains(&cx) || !(0..TILE_S1 as i32).contains(&cz) {
+                    return None;
+                }
+                let weight = BRUSH_RADIUS as f32 - ((dx * dx + dz * dz) as f32).sqrt();
+                (weight > 0.).then_some((cz as usize + cx as usize * TILE_S1, weight))
+            })
+            .collect();
+    let total: f32 = cells.iter().map(|(_, weight)| weight).sum();
+    for (i, weight) in cells {
+        heights[i] -= amount * weight / total;
+    }
+}
+
+/// Rolls a drop of water down from pos, it carves valleys where it speeds up
+/// and leaves sediment where it slows down or reaches the sea
+fn droplet(heights: &mut [f32], mut pos: Vec2) {
+    let max = (TILE_S1 - 1) as f32;
+    let (mut dir, mut speed, mut water, mut sediment) = (Vec2::ZERO, 1., 1., 0.);
+    for _ in 0..DROPLET_LIFETIME {
+        let (height, gradient) = sample(heights, pos);
+        dir = (dir * INERTIA - gradient * (1. - INERTIA)).normalize_or_zero();
+        if height <= WATER_H as f32 || dir == Vec2::ZERO {
+            // sediment fans out where rivers meet the sea or the ground flattens
+            deposit(heights, pos, sediment);
+            return;
+        }
+        let prev = pos;
+        pos += dir;
+        if pos.x < 0. || pos.y < 0. || pos.x >= max || pos.y >= max {
+            return;
+        }
+        let dh = sample(heights, pos).0 - height;
+        let capacity = (-dh * speed * water * CAPACITY).max(MIN_CAPACITY);
+        if sediment > capacity || dh > 0. {
+            // going up fills the hole behind, slowing down drops what's too much
+            let amount = if dh > 0. {
+                dh.min(sediment)
+            } else {
+                (sediment - capacity) * DEPOSIT_RATE
+            };
+            sediment -= amount;
+            deposit(heights, prev, amount);
+        } else {
+            let amount = ((capacity - sediment) * ERODE_RATE).min(-dh);
+            erode(heights, prev, amount);
+            sediment += amount;
+        }
+        speed = (speed * speed - dh * GRAVITY).max(0.).sqrt();
+        water *= 1. - EVAPORATION;
+    }
+}
+
+/// Moves ground down slopes steeper than TALUS
+fn thermal_step(heights: &mut [f32]) {
+    for (x, z) in iproduct!(1..TILE_S1 - 1, 1..TILE_S1 - 1) {
+        let i = z + x * TILE_S1;
+        let (lowest, diff) = [i - 1, i + 1, i - TILE_S1, i + TILE_S1]
+            .into_iter()
+            .map(|j| (j, heights[i] - heights[j]))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        if diff > TALUS {
+            let moved = (diff - TALUS) * THERMAL_RATE;
+            heights[i] -= moved;
+            heights[lowest] += moved;
+        }
+    }
+}
+
+/// Hydraulic and thermal erosion of the terrain. The world is eroded by overlapping tiles
+/// that only depend on the seed, then blended, so columns agree on their borders.
+pub struct Erosion {
+    seed: i32,
+    // scales the droplets and thermal steps, 0 disables erosion
+    quality: f32,
+    tiles: DashMap<(i32, i32), Arc<Tile>>,
+}
+
+impl Erosion {
+    pub fn new(seed: i32, quality: f32) -> Self {
+        Erosion {
+            seed,
+            quality: quality.max(0.),
+            tiles: DashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.quality > 0.
+    }
+
+    fn erode_tile(
+        &self,
+        tile: (i32, i32),
+        terrain: &impl Fn(BlockPos2d, usize) -> Vec<f32>,
+    ) -> Tile {
+        let origin = BlockPos2d {
+            x: tile.0 * TILE_SPACING - TILE_SPACING - TILE_PADDING,
+            z: tile.1 * TILE_SPACING - TILE_SPACING - TILE_PADDING,
+        };
+        let mut heights = terrain(origin, TILE_S1);
+        let mut rng = BlockPos2d {
+            x: tile.0,
+            z: tile.1,
+        }
+        .prng(self.seed ^ EROSION_SALT) as u64
+            | 1;
+        let droplets = ((TILE_S1 * TILE_S1) as f32 * DROPLET_DENSITY * self.quality) as usize;
+        let max = (TILE_S1 - 1) as f32;
+        for _ in 0..droplets {
+            let pos = Vec2::new(unit(&mut rng) * max, unit(&mut rng) * max);
+            droplet(&mut heights, pos);
+        }
+        for _ in 0..(THERMAL_STEPS * self.quality).ceil() as usize {
+            thermal_step(&mut heights);
+        }
+        Tile { origin, heights }
+    }
+
+    fn tile(
+        &self,
+        tile: (i32, i32),
+        terrain: &impl Fn(BlockPos2d, usize) -> Vec<f32>,
+    ) -> Arc<Tile> {
+        if let Some(tile) = self.tiles.get(&tile) {
+            return tile.clone();
+        }
+        // eroded without holding the lock, if another thread was faster its tile is kept
+        let eroded = Arc::new(self.erode_tile(tile, terrain));
+        self.tiles.entry(tile).or_insert(eroded).clone()
+    }
+
+    /// Tiles blended in a square region
+    fn tiles_around(origin: BlockPos2d, size: usize) -> impl Iterator<Item = (i32, i32)> {
+        let span = |start: i32| {
+            start.div_euclid(TILE_SPACING)..=(start + size as i32 - 1).div_euclid(TILE_SPACING) + 1
+        };
+        iproduct!(span(origin.x), span(origin.z))
+    }
+
+    /// Drops the tiles that none of the square regions (origin, size) need
+    pub fn retain(&self, areas: impl IntoIterator<Item = (BlockPos2d, usize)>) {
+        let needed: HashSet<(i32, i32)> = areas
+            .into_iter()
+            .flat_map(|(origin, size)| Self::tiles_around(origin, size))
+            .collect();
+        self.tiles.retain(|tile, _| needed.contains(tile));
+    }
+
+    /// Eroded heights of a square region, indexed with z + x*size.
+    /// `terrain` gives the heights before erosion of a square region, indexed the same way.
+    pub fn grid(
+        &self,
+        origin: BlockPos2d,
+        size: usize,
+        terrain: impl Fn(BlockPos2d, usize) -> Vec<f32>,
+    ) -> Vec<i32> {
+        let tiles: HashMap<(i32, i32), Arc<Tile>> = Self::tiles_around(origin, size)
+            .map(|tile| (tile, self.tile(tile, &terrain)))
+            .collect();
+        (0..size * size)
+            .map(|i| {
+                let (x, z) = (origin.x + (i / size) as i32, origin.z + (i % size) as i32);
+                let (tx, tz) = (x.div_euclid(TILE_SPACING), z.div_euclid(TILE_SPACING));
+                let fx = x.rem_euclid(TILE_SPACING) as f32 / TILE_SPACING as f32;
+                let fz = z.rem_euclid(TILE_SPACING) as f32 / TILE_SPACING as f32;
+                let mut height = 0.;
+                for (dx, dz) in iproduct!(0..2, 0..2) {
+                    let wx = if dx == 0 { 1. - fx } else { fx };
+                    let wz = if dz == 0 { 1. - fz } else { fz };
+                    height += wx * wz * tiles[&(tx + dx, tz + dz)].get(x, z);
+                }
+                height.round() as i32
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a ridge along z, steep enough to be eroded
+    fn terrain(origin: BlockPos2d, size: usize) -> Vec<f32> {
+        (0..size * size)
+            .map(|i| {
+                let x = origin.x + (i / size) as i32;
+                (WATER_H + 80 - (x.abs() * 2).min(100)) as f32
+            })
+            .collect()
+    }
+
+    #[test]
+    fn trimmed_tiles_are_eroded_again_the_same() {
+        let erosion = Erosion::new(3, 1.);
+        let (origin, size) = (BlockPos2d { x: -40, z: 10 }, 70);
+        let heights = erosion.grid(origin, size, terrain);
+        erosion.retain([(origin, size)]);
+        assert!(!erosion.tiles.is_empty());
+        erosion.retain([(BlockPos2d { x: 10_000, z: 0 }, size)]);
+        assert!(erosion.tiles.is_empty());
+        assert_eq!(erosion.grid(origin, size, terrain), heights);
+    }
+}
@@ -0,0 +1,32 @@
+use super::{pipeline::GenStage, terrain_gen::GenerationState};
+use crate::world::{ChunkPos, ColPos, VoxelWorld, CHUNK_S1I};
+
+/// Fills the columns of a world one stage at a time (see `GenPipeline`).
+/// Must only depend on its seed so that columns can be generated in any order.
+pub trait WorldGenerator: Send + Sync {
+    fn seed(&self) -> i32;
+
+    /// How many columns away the features of a column can reach.
+    /// Columns are only finalized once the ones this close are done with their features.
+    fn spill_radius(&self) -> i32 {
+        0
+    }
+
+    /// Drops what was cached to generate columns, keeping what the `needed` ones can use
+    fn trim_caches(&self, _needed: &[ColPos]) {}
+
+    /// Runs a stage of the column
+    fn process_stage(&self, state: &mut GenerationState, stage: GenStage, world: &VoxelWorld);
+}
+
+/// Marks the chunks of the column up to its highest block as loaded, meant for the Finalize stage
+pub fn mark_loaded(state: &GenerationState, world: &VoxelWorld) {
+    let max_chunk_height = state.top_height.unwrap_or(0) / CHUNK_S1I;
+    for y in 0..=max_chunk_height {
+        world.set_loaded(ChunkPos {
+            x: state.col_pos.x,
+            y,
+            z: state.col_pos.z,
+        });
+    }
+}
@@ -0,0 +1,380 @@
+use super::{
+    biomes::{closest_biome, Biome, BiomeTable},
+    config::{asset_path, GenConfig},
+    generator::{mark_loaded, WorldGenerator},
+    pipeline::GenStage,
+    terrain_gen::GenerationState,
+};
+use crate::{
+    block::Block,
+    world::{VoxelWorld, CHUNK_S1, CHUNK_S1I, MAX_GEN_HEIGHT, WATER_H},
+};
+use anyhow::{bail, Context, Result};
+use bevy::{
+    asset::RenderAssetUsages,
+    image::{CompressedImageFormats, Image, ImageSampler, ImageType},
+};
+use std::str::FromStr;
+
+// colors of the biome map, pixels get the biome of the closest one
+pub const BIOME_COLORS: [(Biome, [f32; 3]); 10] = [
+    (Biome::Ocean, [0.1, 0.2, 0.7]),
+    (Biome::Beach, [0.95, 0.9, 0.6]),
+    (Biome::Desert, [0.9, 0.75, 0.3]),
+    (Biome::Plains, [0.55, 0.8, 0.3]),
+    (Biome::Forest, [0.1, 0.5, 0.1]),
+    (Biome::BirchForest, [0.4, 0.65, 0.3]),
+    (Biome::Taiga, [0.15, 0.35, 0.3]),
+    (Biome::Tundra, [0.7, 0.8, 0.85]),
+    (Biome::Mountains, [0.5, 0.5, 0.5]),
+    (Biome::SnowyPeaks, [1., 1., 1.]),
+];
+
+/// What's beyond the bounds of the images
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edges {
+    /// the border pixels stretch out forever
+    Clamp,
+    /// the images repeat
+    Tile,
+}
+
+impl FromStr for Edges {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim() {
+            "clamp" => Edges::Clamp,
+            "tile" => Edges::Tile,
+            _ => bail!("Unknown edges '{}', expected clamp or tile", s),
+        })
+    }
+}
+
+impl Edges {
+    fn apply(self, v: i32, size: u32) -> u32 {
+        match self {
+            Edges::Clamp => v.clamp(0, size as i32 - 1) as u32,
+            Edges::Tile => v.rem_euclid(size as i32) as u32,
+        }
+    }
+}
+
+/// Where the images are and how they're laid on the world. Written as
+/// `path[;biomes=path][;scale=blocks per pixel][;vertical=blocks][;base=blocks][;edges=clamp|tile]`,
+/// with paths relative to the assets folder.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeightmapSettings {
+    pub heights: String,
+    pub biomes: Option<String>,
+    pub horizontal_scale: f32,
+    // height of white pixels above black ones, in blocks
+    pub vertical_scale: f32,
+    // height of black pixels
+    pub base: i32,
+    pub edges: Edges,
+}
+
+impl FromStr for HeightmapSettings {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(';').map(str::trim);
+        let Some(heights) = parts.next().filter(|path| !path.is_empty()) else {
+            bail!("Missing the path of the heightmap");
+        };
+        let mut settings = HeightmapSettings {
+            heights: heights.to_string(),
+            biomes: None,
+            horizontal_scale: 1.,
+            vertical_scale: MAX_GEN_HEIGHT as f32,
+            base: 0,
+            edges: Edges::Clamp,
+        };
+        for part in parts.filter(|part| !part.is_empty()) {
+            let Some((key, value)) = part.split_once('=') else {
+                bail!("Expected key=value, got '{}'", part);
+            };
+            let value = value.trim();
+            match key.trim() {
+                "biomes" => settings.biomes = Some(value.to_string()),
+                "scale" => settings.horizontal_scale = value.parse()?,
+                "vertical" => settings.vertical_scale = value.parse()?,
+                "base" => settings.base = value.parse()?,
+                "edges" => settings.edges = value.parse()?,
+                key => bail!("Unknown heightmap setting '{}'", key),
+            }
+        }
+        if settings.horizontal_scale <= 0. {
+            bail!("The scale of the heightmap must be positive");
+        }
+        if !(0. ..=MAX_GEN_HEIGHT as f32).contains(&settings.vertical_scale) {
+            bail!(
+                "The vertical scale of the heightmap must be within 0..={}",
+                MAX_GEN_HEIGHT
+            );
+        }
+        if !(0..MAX_GEN_HEIGHT as i32).contains(&settings.base) {
+            bail!(
+                "The base of the heightmap must be within 0..{}",
+                MAX_GEN_HEIGHT
+            );
+        }
+        Ok(settings)
+    }
+}
+
+/// Pixels of an image, indexed with x + y*width
+struct Pixels<T> {
+    width: u32,
+    height: u32,
+    values: Vec<T>,
+}
+
+impl<T: Copy> Pixels<T> {
+    fn load(path: &str, pixel: impl Fn(bevy::color::Color) -> T) -> Result<Self> {
+        let path = asset_path(path);
+        let bytes = std::fs::read(&path).with_context(|| format!("Can't read {:?}", path))?;
+        let extension = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default();
+        Self::decode(&bytes, extension, pixel).with_context(|| format!("Can't load {:?}", path))
+    }
+
+    /// Channels are read as stored, without any sRGB conversion
+    fn decode(
+        bytes: &[u8],
+        extension: &str,
+        pixel: impl Fn(bevy::color::Color) -> T,
+    ) -> Result<Self> {
+        let image = Image::from_buffer(
+            bytes,
+            ImageType::Extension(extension),
+            CompressedImageFormats::NONE,
+            false,
+            ImageSampler::Default,
+            RenderAssetUsages::default(),
+        )?;
+        let (width, height) = (image.width(), image.height());
+        let mut values = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                values.push(pixel(image.get_color_at(x, y)?));
+            }
+        }
+        Ok(Pixels {
+            width,
+            height,
+            values,
+        })
+    }
+
+    fn get(&self, x: i32, y: i32, edges: Edges) -> T {
+        let (x, y) = (edges.apply(x, self.width), edges.apply(y, self.height));
+        self.values[(x + y * self.width) as usize]
+    }
+}
+
+/// Terrain from a grayscale heightmap, with the biomes of an optional color map
+/// or from the altitude alone
+pub struct HeightmapGen {
+    seed: i32,
+    settings: HeightmapSettings,
+    heights: Pixels<f32>,
+    biomes: Option<Pixels<Biome>>,
+    biome_table: BiomeTable,
+}
+
+fn closest_color(color: bevy::color::Color) -> Biome {
+    // the map is loaded as Unorm, so the linear channels are the painted values
+    let color = color.to_linear();
+    let dist = |[r, g, b]: [f32; 3]| {
+        (color.red - r).powi(2) + (color.green - g).powi(2) + (color.blue - b).powi(2)
+    };
+    BIOME_COLORS
+        .iter()
+        .min_by(|(_, a), (_, b)| dist(*a).total_cmp(&dist(*b)))
+        .map(|(biome, _)| *biome)
+        .unwrap()
+}
+
+impl HeightmapGen {
+    pub fn new(seed: i32, settings: HeightmapSettings, config: &GenConfig) -> Result<Self> {
+        let heights = Pixels::load(&settings.heights, |color| color.to_linear().red)?;
+        let biomes = match &settings.biomes {
+            Some(path) => Some(Pixels::load(path, closest_color)?),
+            None => None,
+        };
+        Ok(HeightmapGen {
+            seed,
+            settings,
+            heights,
+            biomes,
+            biome_table: config.biomes.clone(),
+        })
+    }
+
+    /// Height of the ground at x,z (in blocks), interpolated between pixels
+    /// and kept below MAX_GEN_HEIGHT
+    pub fn height(&self, x: i32, z: i32) -> i32 {
+        let edges = self.settings.edges;
+        let (px, pz) = (
+            x as f32 / self.settings.horizontal_scale,
+            z as f32 / self.settings.horizontal_scale,
+        );
+        let (x0, z0) = (px.floor() as i32, pz.floor() as i32);
+        let (fx, fz) = (px - x0 as f32, pz - z0 as f32);
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let value = lerp(
+            lerp(
+                self.heights.get(x0, z0, edges),
+                self.heights.get(x0 + 1, z0, edges),
+                fx,
+            ),
+            lerp(
+                self.heights.get(x0, z0 + 1, edges),
+                self.heights.get(x0 + 1, z0 + 1, edges),
+                fx,
+            ),
+            fz,
+        );
+        (self.settings.base + (value * self.settings.vertical_scale) as i32)
+            .clamp(0, MAX_GEN_HEIGHT as i32 - 1)
+    }
+
+    /// Biome at x,z, from the biome map if there's one
+    pub fn biome(&self, x: i32, z: i32, height: i32) -> Biome {
+        if let Some(biomes) = &self.biomes {
+            let scale = self.settings.horizontal_scale;
+            return biomes.get(
+                (x as f32 / scale).floor() as i32,
+                (z as f32 / scale).floor() as i32,
+                self.settings.edges,
+            );
+        }
+        let altitude = (height as f32 / MAX_GEN_HEIGHT as f32).clamp(0., 1.);
+        closest_biome(&self.biome_table, 0.5, 0.5, altitude)
+    }
+
+    /// Fills the blocks of a row of x, returns the highest one
+    fn fill_row(&self, world: &VoxelWorld, state: &GenerationState, x: usize) -> i32 {
+        let mut runs = Vec::with_capacity(CHUNK_S1 * 4);
+        let mut highest = WATER_H;
+        for z in 0..CHUNK_S1 {
+            let top = state.heights[z + x * CHUNK_S1];
+            let biome = state.biomes[z + x * CHUNK_S1];
+            let (soil, soil_depth) = biome.soil();
+            runs.push(((x, z), 0, top - 1 - soil_depth, Block::Stone));
+            runs.push(((x, z), top - soil_depth, top - 1, soil));
+            runs.push(((x, z), top, top, biome.surface()));
+            runs.push(((x, z), top + 1, WATER_H, Block::Water));
+            highest = highest.max(top);
+        }
+        // empty runs (bottom > top) are skipped
+        world.set_col_runs(state.col_pos, &runs);
+        highest
+    }
+}
+
+impl WorldGenerator for HeightmapGen {
+    fn seed(&self) -> i32 {
+        self.seed
+    }
+
+    fn process_stage(&self, state: &mut GenerationState, stage: GenStage, world: &VoxelWorld) {
+        match stage {
+            GenStage::Terrain => {
+                let (x0, z0) = (state.col_pos.x * CHUNK_S1I, state.col_pos.z * CHUNK_S1I);
+                for i in 0..CHUNK_S1 * CHUNK_S1 {
+                    let (x, z) = (x0 + (i / CHUNK_S1) as i32, z0 + (i % CHUNK_S1) as i32);
+                    let height = self.height(x, z);
+                    state.heights.push(height);
+                    state.biomes.push(self.biome(x, z, height));
+                }
+                world
+                    .biomes
+                    .insert(state.col_pos, state.biomes.clone().into_boxed_slice());
+                // Fill the column one row at a time
+                for x in 0..CHUNK_S1 {
+                    let top = self.fill_row(world, state, x);
+                    state.top_height = Some(state.top_height.unwrap_or(0).max(top));
+                }
+            }
+            GenStage::Carving | GenStage::Features => {}
+            GenStage::Finalize => mark_loaded(state, world),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_from_str() {
+        let settings: HeightmapSettings =
+            "maps/alps.png; biomes=maps/alps_biomes.png ;scale=2;vertical=150;base=20;edges=tile"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            settings,
+            HeightmapSettings {
+                heights: "maps/alps.png".to_string(),
+                biomes: Some("maps/alps_biomes.png".to_string()),
+                horizontal_scale: 2.,
+                vertical_scale: 150.,
+                base: 20,
+                edges: Edges::Tile,
+            }
+        );
+        let defaults: HeightmapSettings = "maps/alps.png".parse().unwrap();
+        assert_eq!(defaults.edges, Edges::Clamp);
+        assert_eq!(defaults.vertical_scale, MAX_GEN_HEIGHT as f32);
+    }
+
+    #[test]
+    fn settings_out_of_range() {
+        for bad in [
+            "",
+            ";scale=2",
+            "a.png;scale=0",
+            "a.png;scale=-1",
+            "a.png;vertical=-10",
+            "a.png;vertical=100000",
+            "a.png;base=-1",
+            "a.png;base=400",
+            "a.png;edges=mirror",
+            "a.png;depth=3",
+            "a.png;scale",
+        ] {
+            assert!(bad.parse::<HeightmapSettings>().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn edges_apply() {
+        assert_eq!(Edges::Clamp.apply(-5, 10), 0);
+        assert_eq!(Edges::Clamp.apply(4, 10), 4);
+        assert_eq!(Edges::Clamp.apply(12, 10), 9);
+        assert_eq!(Edges::Tile.apply(-1, 10), 9);
+        assert_eq!(Edges::Tile.apply(4, 10), 4);
+        assert_eq!(Edges::Tile.apply(23, 10), 3);
+    }
+
+    #[test]
+    fn biome_colors_map_back() {
+        let mut png = Vec::new();
+        let pixels = BIOME_COLORS
+            .iter()
+            .flat_map(|(_, rgb)| rgb.map(|channel| (channel * 255.).round() as u8));
+        image::RgbImage::from_vec(BIOME_COLORS.len() as u32, 1, pixels.collect())
+            .unwrap()
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let biomes = Pixels::decode(&png, "png", closest_color).unwrap();
+        for (x, (biome, _)) in BIOME_COLORS.iter().enumerate() {
+            assert_eq!(biomes.get(x as i32, 0, Edges::Clamp), *biome);
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use super::{
+    biomes::Biome,
+    config::GenConfig,
+    heightmap_gen::BIOME_COLORS,
+    pregen::{generate_tiles, tiles},
+    presets::WorldPreset,
+    terrain_gen::WorldSeed,
+};
+use crate::{
+    block::{Block, Face},
+    render::get_color_from_block,
+    world::{BlockPos2d, ChunkPos, ColPos, VoxelWorld, CHUNK_S1, CHUNK_S1I, MAX_GEN_HEIGHT},
+};
+use anyhow::{bail, Context, Result};
+use bevy::{
+    asset::RenderAssetUsages,
+    image::Image,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+};
+use itertools::iproduct;
+use parking_lot::Mutex;
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+// how much brighter (or darker) a pixel gets per block above (or below) the one to its north-west
+const SLOPE_SHADING: f32 = 0.08;
+const MIN_SHADE: f32 = 0.6;
+const MAX_SHADE: f32 = 1.4;
+// how much darker water gets per block down to the ground under it
+const DEPTH_SHADING: f32 = 0.05;
+const MIN_DEPTH_SHADE: f32 = 0.3;
+
+/// What the pixels of the map show
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapLayer {
+    /// the color of the top block, shaded by the slope and darker over deep water
+    Blocks,
+    /// the biome colors of `HeightmapSettings`, so the map can be fed back to the heightmap preset
+    Biomes,
+    /// the height of the top block, black at 0 and white at MAX_GEN_HEIGHT
+    Height,
+}
+
+impl FromStr for MapLayer {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.trim() {
+            "blocks" => MapLayer::Blocks,
+            "biomes" => MapLayer::Biomes,
+            "height" => MapLayer::Height,
+            _ => bail!("Unknown layer '{}', expected blocks, biomes or height", s),
+        })
+    }
+}
+
+/// What `riverbed map` draws. Usage:
+/// `riverbed map --from X,Z --to X,Z [--layer blocks|biomes|height] [--seed S] [--preset P]
+/// [--saved DIR] [--out FILE] [--threads N]`, with the corners in columns (both included).
+/// The columns are read from the chunks saved in DIR (as `riverbed pregen --out DIR` writes them)
+/// or generated when there's none.
+pub struct MapOptions {
+    pub from: ColPos,
+    pub to: ColPos,
+    pub layer: MapLayer,
+    pub seed: i32,
+    pub preset: WorldPreset,
+    pub saved: Option<PathBuf>,
+    pub out: PathBuf,
+    pub threads: usize,
+}
+
+fn col_from_str(s: &str) -> Result<ColPos> {
+    let Some((x, z)) = s.split_once(',') else {
+        bail!("Expected X,Z, got '{}'", s);
+    };
+    Ok(ColPos {
+        x: x.trim().parse()?,
+        z: z.trim().parse()?,
+    })
+}
+
+impl MapOptions {
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let mut options = MapOptions {
+            from: ColPos { x: -4, z: -4 },
+            to: ColPos { x: 3, z: 3 },
+            layer: MapLayer::Blocks,
+            seed: WorldSeed::default().0,
+            preset: WorldPreset::default(),
+            saved: None,
+            out: PathBuf::from("map.png"),
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(value) = args.next() else {
+                bail!("Missing a value after {}", arg);
+            };
+            match arg.as_str() {
+                "--from" => options.from = col_from_str(value)?,
+                "--to" => options.to = col_from_str(value)?,
+                "--layer" => options.layer = value.parse()?,
+                "--seed" => options.seed = value.parse()?,
+                "--preset" => options.preset = value.parse()?,
+                "--saved" => options.saved = Some(PathBuf::from(value)),
+                "--out" => options.out = PathBuf::from(value),
+                "--threads" => options.threads = value.parse::<usize>()?.max(1),
+                _ => bail!("Unknown option {}", arg),
+            }
+        }
+        if options.from.x > options.to.x || options.from.z > options.to.z {
+            bail!("--from must be north-west of --to");
+        }
+        if options.saved.is_some() && options.layer == MapLayer::Biomes {
+            bail!("Biomes aren't saved with the chunks, the biome layer needs the generator");
+        }
+        Ok(options)
+    }
+
+    fn contains(&self, col: ColPos) -> bool {
+        (self.from.x..=self.to.x).contains(&col.x) && (self.from.z..=self.to.z).contains(&col.z)
+    }
+
+    fn cols(&self) -> impl Iterator<Item = ColPos> {
+        iproduct!(self.from.x..=self.to.x, self.from.z..=self.to.z).map(|(x, z)| ColPos { x, z })
+    }
+
+    /// Size of the map in blocks (and pixels)
+    fn size(&self) -> (usize, usize) {
+        (
+            (self.to.x - self.from.x + 1) as usize * CHUNK_S1,
+            (self.to.z - self.from.z + 1) as usize * CHUNK_S1,
+        )
+    }
+}
+
+/// The top of the world at a block, what the pixels are drawn from
+#[derive(Clone, Copy)]
+struct Surface {
+    block: Block,
+    height: i32,
+    // blocks that can be seen through (like water) between the top and the opaque ground
+    depth: i32,
+    biome: Option<Biome>,
+}
+
+/// Surfaces of the map, indexed with x + z*width
+struct Surfaces {
+    width: usize,
+    values: Vec<Option<Surface>>,
+}
+
+impl Surfaces {
+    /// Copies the surface of the columns from the world
+    fn read(&mut self, options: &MapOptions, world: &VoxelWorld, cols: &[ColPos]) {
+        for col_pos in cols {
+            let x0 = ((col_pos.x - options.from.x) * CHUNK_S1I) as usize;
+            let z0 = ((col_pos.z - options.from.z) * CHUNK_S1I) as usize;
+            for (dx, dz) in iproduct!(0..CHUNK_S1, 0..CHUNK_S1) {
+                let pos = BlockPos2d::from((*col_pos, (dx, dz)));
+                let (block, height) = world.top_block(pos);
+                self.values[x0 + dx + (z0 + dz) * self.width] =
+                    (block != Block::Air).then(|| Surface {
+                        block,
+                        height,
+                        depth: height - world.opaque_height(pos).unwrap_or(0),
+                        biome: world.biome(pos),
+                    });
+            }
+        }
+    }
+
+    fn height(&self, x: usize, z: usize) -> Option<i32> {
+        self.values[x + z * self.width].map(|surface| surface.height)
+    }
+}
+
+/// Loads the saved chunks of the area
+fn load_saved(options: &MapOptions, dir: &Path) -> Result<VoxelWorld> {
+    let world = VoxelWorld::new();
+    let entries = std::fs::read_dir(dir).with_context(|| format!("Can't read {:?}", dir))?;
+    for entry in entries {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let Some(coords) = name.strip_suffix(".chunk") else {
+            continue;
+        };
+        let coords: Vec<i32> = coords.split('_').filter_map(|c| c.parse().ok()).collect();
+        let [x, y, z] = coords[..] else {
+            continue;
+        };
+        let chunk_pos = ChunkPos { x, y, z };
+        if !options.contains(chunk_pos.into()) {
+            continue;
+        }
+        let bytes = std::fs::read(&path).with_context(|| format!("Can't read {:?}", path))?;
+        world.load_chunk(chunk_pos, &bytes);
+    }
+    Ok(world)
+}
+
+fn biome_color(biome: Biome) -> [f32; 3] {
+    BIOME_COLORS
+        .iter()
+        .find(|(b, _)| *b == biome)
+        .map_or([0., 0., 0.], |(_, color)| *color)
+}
+
+/// Color of the pixel at x,z, black where there's nothing
+fn pixel(surfaces: &Surfaces, layer: MapLayer, x: usize, z: usize) -> [f32; 3] {
+    let Some(surface) = surfaces.values[x + z * surfaces.width] else {
+        return [0., 0., 0.];
+    };
+    match layer {
+        MapLayer::Blocks => {
+            let [r, g, b, _] = get_color_from_block(&surface.block, &Face::Up);
+            // lit from the north-west, like a relief map
+            let shade = match (x.checked_sub(1), z.checked_sub(1)) {
+                (Some(nx), Some(nz)) => surfaces.height(nx, nz).map_or(1., |north_west| {
+                    1. + (surface.height - north_west) as f32 * SLOPE_SHADING
+                }),
+                _ => 1.,
+            }
+            .clamp(MIN_SHADE, MAX_SHADE)
+                * (1. - surface.depth as f32 * DEPTH_SHADING).max(MIN_DEPTH_SHADE);
+            [r * shade, g * shade, b * shade]
+        }
+        MapLayer::Biomes => surface.biome.map_or([0., 0., 0.], biome_color),
+        MapLayer::Height => [(surface.height as f32 / MAX_GEN_HEIGHT as f32).clamp(0., 1.); 3],
+    }
+}
+
+/// Draws a top-down map of the area into a PNG, from the saved chunks or the generator
+pub fn map(options: &MapOptions) -> Result<()> {
+    let (width, height) = options.size();
+    let surfaces = Mutex::new(Surfaces {
+        width,
+        values: vec![None; width * height],
+    });
+    if let Some(dir) = &options.saved {
+        let world = load_saved(options, dir)?;
+        let cols: Vec<ColPos> = options.cols().collect();
+        surfaces.lock().read(options, &world, &cols);
+    } else {
+        let config = GenConfig::from_disk()?;
+        let generator = options.preset.build(options.seed, &config);
+        println!(
+            "Generating {}x{} blocks ({:?} world, seed {}) on {} threads",
+            width, height, options.preset, options.seed, options.threads
+        );
+        generate_tiles(
+            generator.as_ref(),
+            &tiles(options.cols()),
+            options.threads,
+            |world, cols| {
+                surfaces.lock().read(options, world, cols);
+                Ok(())
+            },
+        )?;
+    }
+    let surfaces = surfaces.into_inner();
+    let mut data = Vec::with_capacity(width * height * 4);
+    for (z, x) in iproduct!(0..height, 0..width) {
+        let color = pixel(&surfaces, options.layer, x, z);
+        data.extend(color.map(|c| (c.clamp(0., 1.) * 255.).round() as u8));
+        data.push(255);
+    }
+    let image = Image::new(
+        Extent3d {
+            width: width as u32,
+            height: height as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::default(),
+    );
+    image
+        .try_into_dynamic()?
+        .save(&options.out)
+        .with_context(|| format!("Can't write {:?}", options.out))?;
+    println!("Saved the map to {:?}", options.out);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::BlockPos;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn options_from_args() {
+        let options = MapOptions::from_args(&args(
+            "--from -2,-1 --to 1,0 --layer height --seed 5 --preset void --out a.png --threads 3",
+        ))
+        .unwrap();
+        assert_eq!(options.from, ColPos { x: -2, z: -1 });
+        assert_eq!(options.to, ColPos { x: 1, z: 0 });
+        assert_eq!(options.layer, MapLayer::Height);
+        assert_eq!(options.seed, 5);
+        assert_eq!(options.preset, WorldPreset::Void);
+        assert_eq!(options.out, PathBuf::from("a.png"));
+        assert_eq!(options.threads, 3);
+        assert_eq!(options.size(), (4 * CHUNK_S1, 2 * CHUNK_S1));
+        assert_eq!(options.cols().count(), 8);
+        assert!(options.cols().all(|col| options.contains(col)));
+        assert!(!options.contains(ColPos { x: 2, z: 0 }));
+    }
+
+    #[test]
+    fn bad_args() {
+        for bad in [
+            "--from 1,1 --to 0,0",
+            "--from 0,1 --to 3,0",
+            "--from 1",
+            "--layer caves",
+            "--saved pregen --layer biomes",
+            "--to",
+            "--zoom 2",
+        ] {
+            assert!(MapOptions::from_args(&args(bad)).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn deep_water_is_darker() {
+        let world = VoxelWorld::new();
+        for (x, floor) in [(0, 60), (1, 40)] {
+            world.set_block(BlockPos::new(x, floor, 0), Block::Sand, false);
+            for y in floor + 1..=63 {
+                world.set_block(BlockPos::new(x, y, 0), Block::Water, false);
+            }
+        }
+        let options = MapOptions::from_args(&args("--from 0,0 --to 0,0")).unwrap();
+        let mut surfaces = Surfaces {
+            width: CHUNK_S1,
+            values: vec![None; CHUNK_S1 * CHUNK_S1],
+        };
+        surfaces.read(&options, &world, &[ColPos { x: 0, z: 0 }]);
+        let shallow = pixel(&surfaces, MapLayer::Blocks, 0, 0);
+        let deep = pixel(&surfaces, MapLayer::Blocks, 1, 0);
+        assert!(deep[2] < shallow[2]);
+        assert_eq!(surfaces.values[1].unwrap().depth, 23);
+    }
+}
@@ -1,7 +1,17 @@
+pub mod biomes;
+pub mod caves;
+pub mod config;
 mod earth_gen;
+pub mod erosion;
+pub mod generator;
+pub mod heightmap_gen;
+pub mod map;
+pub mod noise;
+pub mod ores;
+pub mod pipeline;
+pub mod pregen;
+pub mod presets;
+pub mod rivers;
 pub mod terrain_gen;
-use std::ops::Range;
-
-use crate::block::Block;
-
-type Soils = Vec<([Range<f32>; 2], Block)>;
+pub mod trees;
+pub mod verify;
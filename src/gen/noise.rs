@@ -0,0 +1,443 @@
+use crate::world::BlockPos2d;
+use std::simd::{
+    num::{SimdFloat, SimdInt, SimdUint},
+    Simd, StdFloat,
+};
+
+pub const LANES: usize = 8;
+pub type F32s = Simd<f32, LANES>;
+type I32s = Simd<i32, LANES>;
+type Indices = Simd<usize, LANES>;
+
+const DIAG: f32 = std::f32::consts::FRAC_1_SQRT_2;
+const GRAD2_X: [f32; 8] = [1., -1., 0., 0., DIAG, -DIAG, DIAG, -DIAG];
+const GRAD2_Z: [f32; 8] = [0., 0., 1., -1., DIAG, DIAG, -DIAG, -DIAG];
+// the 12 cube edges, padded to 16 so the hash can be masked instead of taken modulo
+const GRAD3: [(f32, f32, f32); 16] = [
+    (1., 1., 0.),
+    (-1., 1., 0.),
+    (1., -1., 0.),
+    (-1., -1., 0.),
+    (1., 0., 1.),
+    (-1., 0., 1.),
+    (1., 0., -1.),
+    (-1., 0., -1.),
+    (0., 1., 1.),
+    (0., -1., 1.),
+    (0., 1., -1.),
+    (0., -1., -1.),
+    (1., 1., 0.),
+    (-1., 1., 0.),
+    (0., -1., 1.),
+    (0., -1., -1.),
+];
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6. - 15.) + 10.)
+}
+
+fn fade_simd(t: F32s) -> F32s {
+    t * t * t * (t * (t * F32s::splat(6.) - F32s::splat(15.)) + F32s::splat(10.))
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn lerp_simd(a: F32s, b: F32s, t: F32s) -> F32s {
+    a + (b - a) * t
+}
+
+/// Permutation table shared by the lattice noises, shuffled by the seed
+fn permutation(seed: i32) -> [u8; 512] {
+    let mut table: [u8; 256] = std::array::from_fn(|i| i as u8);
+    // Fisher-Yates shuffle driven by the position prng
+    for i in (1..256).rev() {
+        let j = BlockPos2d { x: i as i32, z: 0 }.prng(seed) % (i + 1);
+        table.swap(i, j);
+    }
+    std::array::from_fn(|i| table[i & 255])
+}
+
+pub trait Noise {
+    /// roughly in [-1, 1]
+    fn get2(&self, x: f32, z: f32) -> f32;
+    /// roughly in [-1, 1]
+    fn get3(&self, x: f32, y: f32, z: f32) -> f32;
+}
+
+/// Seeded Perlin noise, with SIMD versions for batch evaluation
+pub struct Perlin {
+    perm: [u8; 512],
+}
+
+impl Perlin {
+    pub fn new(seed: i32) -> Self {
+        Perlin {
+            perm: permutation(seed),
+        }
+    }
+
+    fn hash2(&self, x: i32, z: i32) -> usize {
+        self.perm[self.perm[(x & 255) as usize] as usize + (z & 255) as usize] as usize
+    }
+
+    fn hash3(&self, x: i32, y: i32, z: i32) -> usize {
+        self.perm[self.hash2(x, y) + (z & 255) as usize] as usize
+    }
+
+    fn grad2(&self, x: i32, z: i32, dx: f32, dz: f32) -> f32 {
+        let h = self.hash2(x, z) & 7;
+        GRAD2_X[h] * dx + GRAD2_Z[h] * dz
+    }
+
+    fn grad3(&self, x: i32, y: i32, z: i32, dx: f32, dy: f32, dz: f32) -> f32 {
+        let (gx, gy, gz) = GRAD3[self.hash3(x, y, z) & 15];
+        gx * dx + gy * dy + gz * dz
+    }
+
+    fn gather_perm(&self, idx: Indices) -> Indices {
+        Simd::<u8, LANES>::gather_or_default(&self.perm, idx).cast()
+    }
+
+    fn hash2_simd(&self, x: I32s, z: I32s) -> Indices {
+        let mask = I32s::splat(255);
+        let hx = self.gather_perm((x & mask).cast());
+        self.gather_perm(hx + (z & mask).cast())
+    }
+
+    fn grad2_simd(&self, x: I32s, z: I32s, dx: F32s, dz: F32s) -> F32s {
+        let h = self.hash2_simd(x, z) & Indices::splat(7);
+        F32s::gather_or_default(&GRAD2_X, h) * dx + F32s::gather_or_default(&GRAD2_Z, h) * dz
+    }
+
+    pub fn get2_simd(&self, x: F32s, z: F32s) -> F32s {
+        let (x0, z0) = (x.floor(), z.floor());
+        let (dx, dz) = (x - x0, z - z0);
+        let (xi, zi) = (x0.cast::<i32>(), z0.cast::<i32>());
+        let (u, v) = (fade_simd(dx), fade_simd(dz));
+        let (one, i1) = (F32s::splat(1.), I32s::splat(1));
+        let a = lerp_simd(
+            self.grad2_simd(xi, zi, dx, dz),
+            self.grad2_simd(xi + i1, zi, dx - one, dz),
+            u,
+        );
+        let b = lerp_simd(
+            self.grad2_simd(xi, zi + i1, dx, dz - one),
+            self.grad2_simd(xi + i1, zi + i1, dx - one, dz - one),
+            u,
+        );
+        lerp_simd(a, b, v) * F32s::splat(std::f32::consts::SQRT_2)
+    }
+}
+
+impl Noise for Perlin {
+    fn get2(&self, x: f32, z: f32) -> f32 {
+        let (x0, z0) = (x.floor(), z.floor());
+        let (dx, dz) = (x - x0, z - z0);
+        let (x0, z0) = (x0 as i32, z0 as i32);
+        let (u, v) = (fade(dx), fade(dz));
+        let a = lerp(
+            self.grad2(x0, z0, dx, dz),
+            self.grad2(x0 + 1, z0, dx - 1., dz),
+            u,
+        );
+        let b = lerp(
+            self.grad2(x0, z0 + 1, dx, dz - 1.),
+            self.grad2(x0 + 1, z0 + 1, dx - 1., dz - 1.),
+            u,
+        );
+        lerp(a, b, v) * std::f32::consts::SQRT_2
+    }
+
+    fn get3(&self, x: f32, y: f32, z: f32) -> f32 {
+        let (x0, y0, z0) = (x.floor(), y.floor(), z.floor());
+        let (dx, dy, dz) = (x - x0, y - y0, z - z0);
+        let (x0, y0, z0) = (x0 as i32, y0 as i32, z0 as i32);
+        let (u, v, w) = (fade(dx), fade(dy), fade(dz));
+        let corner = |ox: i32, oy: i32, oz: i32| {
+            self.grad3(
+                x0 + ox,
+                y0 + oy,
+                z0 + oz,
+                dx - ox as f32,
+                dy - oy as f32,
+                dz - oz as f32,
+            )
+        };
+        let y_lerp = |oz: i32| {
+            lerp(
+                lerp(corner(0, 0, oz), corner(1, 0, oz), u),
+                lerp(corner(0, 1, oz), corner(1, 1, oz), u),
+                v,
+            )
+        };
+        lerp(y_lerp(0), y_lerp(1), w)
+    }
+}
+
+/// Seeded simplex noise, cheaper than Perlin in 3D and without its axis-aligned artifacts
+pub struct Simplex {
+    perm: [u8; 512],
+}
+
+const SKEW_2D: f32 = 0.366_025_42; // (sqrt(3) - 1) / 2
+const UNSKEW_2D: f32 = 0.211_324_87; // (3 - sqrt(3)) / 6
+const SKEW_3D: f32 = 1. / 3.;
+const UNSKEW_3D: f32 = 1. / 6.;
+
+impl Simplex {
+    pub fn new(seed: i32) -> Self {
+        Simplex {
+            perm: permutation(seed),
+        }
+    }
+
+    fn hash2(&self, x: i32, z: i32) -> usize {
+        self.perm[self.perm[(x & 255) as usize] as usize + (z & 255) as usize] as usize
+    }
+
+    fn hash3(&self, x: i32, y: i32, z: i32) -> usize {
+        self.perm[self.hash2(x, y) + (z & 255) as usize] as usize
+    }
+}
+
+impl Noise for Simplex {
+    fn get2(&self, x: f32, z: f32) -> f32 {
+        let s = (x + z) * SKEW_2D;
+        let (i, j) = ((x + s).floor(), (z + s).floor());
+        let t = (i + j) * UNSKEW_2D;
+        let (x0, z0) = (x - i + t, z - j + t);
+        let (i, j) = (i as i32, j as i32);
+        // which of the cell's two triangles we're in
+        let (i1, j1) = if x0 > z0 { (1, 0) } else { (0, 1) };
+        let corners = [
+            (0, 0, x0, z0),
+            (
+                i1,
+                j1,
+                x0 - i1 as f32 + UNSKEW_2D,
+                z0 - j1 as f32 + UNSKEW_2D,
+            ),
+            (1, 1, x0 - 1. + 2. * UNSKEW_2D, z0 - 1. + 2. * UNSKEW_2D),
+        ];
+        let mut total = 0.;
+        for (oi, oj, dx, dz) in corners {
+            let a = 0.5 - dx * dx - dz * dz;
+            if a > 0. {
+                let h = self.hash2(i + oi, j + oj) & 7;
+                total += a.powi(4) * (GRAD2_X[h] * dx + GRAD2_Z[h] * dz);
+            }
+        }
+        total * 70.
+    }
+
+    fn get3(&self, x: f32, y: f32, z: f32) -> f32 {
+        let s = (x + y + z) * SKEW_3D;
+        let (i, j, k) = ((x + s).floor(), (y + s).floor(), (z + s).floor());
+        let t = (i + j + k) * UNSKEW_3D;
+        let (x0, y0, z0) = (x - i + t, y - j + t, z - k + t);
+        let (i, j, k) = (i as i32, j as i32, k as i32);
+        // which of the cell's six tetrahedra we're in
+        let (o1, o2) = if x0 >= y0 {
+            if y0 >= z0 {
+                ((1, 0, 0), (1, 1, 0))
+            } else if x0 >= z0 {
+                ((1, 0, 0), (1, 0, 1))
+            } else {
+                ((0, 0, 1), (1, 0, 1))
+            }
+        } else if y0 < z0 {
+            ((0, 0, 1), (0, 1, 1))
+        } else if x0 < z0 {
+            ((0, 1, 0), (0, 1, 1))
+        } else {
+            ((0, 1, 0), (1, 1, 0))
+        };
+        let mut total = 0.;
+        for (n, (oi, oj, ok)) in [(0, 0, 0), o1, o2, (1, 1, 1)].into_iter().enumerate() {
+            let offset = n as f32 * UNSKEW_3D;
+            let (dx, dy, dz) = (
+                x0 - oi as f32 + offset,
+                y0 - oj as f32 + offset,
+                z0 - ok as f32 + offset,
+            );
+            let a = 0.6 - dx * dx - dy * dy - dz * dz;
+            if a > 0. {
+                let (gx, gy, gz) = GRAD3[self.hash3(i + oi, j + oj, k + ok) & 15];
+                total += a.powi(4) * (gx * dx + gy * dy + gz * dz);
+            }
+        }
+        total * 32.
+    }
+}
+
+/// Fractal Brownian motion: sums octaves of a noise, normalized to roughly [-1, 1]
+pub struct Fbm<N = Perlin> {
+    noise: N,
+    octaves: u32,
+    frequency: f32,
+    lacunarity: f32,
+    gain: f32,
+}
+
+// offsets each octave so their lattices don't line up at the origin
+const OCTAVE_OFFSET: f32 = 17.31;
+
+impl Fbm<Perlin> {
+    pub fn new(seed: i32, octaves: u32, frequency: f32) -> Self {
+        Self::with_noise(Perlin::new(seed), octaves, frequency)
+    }
+
+    pub fn get2_simd(&self, x: F32s, z: F32s) -> F32s {
+        let mut total = F32s::splat(0.);
+        let mut amplitude = 1.;
+        let mut max_amplitude = 0.;
+        let mut frequency = self.frequency;
+        for octave in 0..self.octaves {
+            let offset = F32s::splat(octave as f32 * OCTAVE_OFFSET);
+            let freq = F32s::splat(frequency);
+            total +=
+                self.noise.get2_simd(x * freq + offset, z * freq + offset) * F32s::splat(amplitude);
+            max_amplitude += amplitude;
+            amplitude *= self.gain;
+            frequency *= self.lacunarity;
+        }
+        total / F32s::splat(max_amplitude)
+    }
+
+    /// Evaluates a size×size grid of blocks starting at `origin`, indexed with z + x*size.
+    /// The z axis is spread over SIMD lanes.
+    pub fn grid2(&self, origin: BlockPos2d, size: usize, warp: Option<&DomainWarp>) -> Vec<f32> {
+        let mut res = vec![0.; size * size];
+        let lane_offsets = F32s::from_array(std::array::from_fn(|i| i as f32));
+        for x in 0..size {
+            let xs = F32s::splat((origin.x + x as i32) as f32);
+            for z in (0..size).step_by(LANES) {
+                let zs = F32s::splat((origin.z + z as i32) as f32) + lane_offsets;
+                let (xs, zs) = match warp {
+                    Some(warp) => warp.warp2_simd(xs, zs),
+                    None => (xs, zs),
+                };
+                let values = self.get2_simd(xs, zs);
+                let n = LANES.min(size - z);
+                let i = z + x * size;
+                res[i..(i + n)].copy_from_slice(&values.as_array()[..n]);
+            }
+        }
+        res
+    }
+}
+
+impl<N: Noise> Fbm<N> {
+    pub fn with_noise(noise: N, octaves: u32, frequency: f32) -> Self {
+        Fbm {
+            noise,
+            octaves,
+            frequency,
+            lacunarity: 2.,
+            gain: 0.5,
+        }
+    }
+
+    pub fn get2(&self, x: f32, z: f32) -> f32 {
+        let mut total = 0.;
+        let mut amplitude = 1.;
+        let mut max_amplitude = 0.;
+        let mut frequency = self.frequency;
+        for octave in 0..self.octaves {
+            let offset = octave as f32 * OCTAVE_OFFSET;
+            total += self
+                .noise
+                .get2(x * frequency + offset, z * frequency + offset)
+                * amplitude;
+            max_amplitude += amplitude;
+            amplitude *= self.gain;
+            frequency *= self.lacunarity;
+        }
+        total / max_amplitude
+    }
+
+    pub fn get3(&self, x: f32, y: f32, z: f32) -> f32 {
+        let mut total = 0.;
+        let mut amplitude = 1.;
+        let mut max_amplitude = 0.;
+        let mut frequency = self.frequency;
+        for octave in 0..self.octaves {
+            let offset = octave as f32 * OCTAVE_OFFSET;
+            total += self.noise.get3(
+                x * frequency + offset,
+                y * frequency + offset,
+                z * frequency + offset,
+            ) * amplitude;
+            max_amplitude += amplitude;
+            amplitude *= self.gain;
+            frequency *= self.lacunarity;
+        }
+        total / max_amplitude
+    }
+}
+
+/// Displaces positions by low frequency noise before sampling, breaks up the blobby look of fbm
+pub struct DomainWarp {
+    x: Fbm,
+    z: Fbm,
+    // max displacement, in blocks
+    strength: f32,
+}
+
+impl DomainWarp {
+    pub fn new(seed: i32, octaves: u32, frequency: f32, strength: f32) -> Self {
+        DomainWarp {
+            x: Fbm::new(seed, octaves, frequency),
+            z: Fbm::new(seed.wrapping_add(2), octaves, frequency),
+            strength,
+        }
+    }
+
+    /// Same as `warp2_simd` (bit for bit) for a single position
+    pub fn warp2(&self, x: f32, z: f32) -> (f32, f32) {
+        let (x, z) = self.warp2_simd(F32s::splat(x), F32s::splat(z));
+        (x[0], z[0])
+    }
+
+    pub fn warp2_simd(&self, x: F32s, z: F32s) -> (F32s, F32s) {
+        let strength = F32s::splat(self.strength);
+        (
+            x + self.x.get2_simd(x, z) * strength,
+            z + self.z.get2_simd(x, z) * strength,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grid2_matches_get2() {
+        let fbm = Fbm::new(42, 5, 1. / 300.);
+        let warp = DomainWarp::new(7, 3, 1. / 512., 96.);
+        // not a multiple of LANES, and across the origin
+        let (origin, size) = (BlockPos2d { x: -30, z: -5 }, 37);
+        let plain = fbm.grid2(origin, size, None);
+        let warped = fbm.grid2(origin, size, Some(&warp));
+        for (x, z) in itertools::iproduct!(0..size, 0..size) {
+            let (bx, bz) = ((origin.x + x as i32) as f32, (origin.z + z as i32) as f32);
+            assert_eq!(plain[z + x * size].to_bits(), fbm.get2(bx, bz).to_bits());
+            let (wx, wz) = warp.warp2(bx, bz);
+            assert_eq!(warped[z + x * size].to_bits(), fbm.get2(wx, wz).to_bits());
+        }
+    }
+
+    #[test]
+    fn perlin_simd_matches_scalar() {
+        let perlin = Perlin::new(-3);
+        let xs = F32s::from_array([0., 0.5, -0.25, 255.9, 256.1, -1000.3, 12345.6, 1e-3]);
+        let zs = F32s::from_array([0., -7.75, 3.5, 0.1, -256.1, 999.9, -4321.2, 88.8]);
+        let simd = perlin.get2_simd(xs, zs);
+        for i in 0..LANES {
+            assert_eq!(simd[i].to_bits(), perlin.get2(xs[i], zs[i]).to_bits());
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use super::{biomes::Biome, earth_gen::Earth, generator::WorldGenerator, pipeline::Run};
+use crate::{
+    block::Block,
+    utils::math::range_from_str,
+    world::{BlockPos, BlockPos2d, ColPos, ColedPos, CHUNK_S1I},
+};
+use anyhow::{bail, Result};
+use bevy::math::Vec3;
+use itertools::iproduct;
+use std::{io::Read, ops::Range, str::FromStr};
+
+const ORE_SALT: i32 = 0x0DE5;
+// veins start in a column and can't reach further than its neighbours
+const MAX_VEIN_LENGTH: u32 = 40;
+const VEIN_STEP: f32 = 1.5;
+// each step of a vein adds a blob of blocks within this (squared) distance
+const BLOB_RADIUS2: i32 = 2;
+
+/// How an ore spreads: `veins` per column on average (can be fractional),
+/// starting between `heights` (in blocks), only in `biomes` if there are any.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OreRule {
+    pub ore: Block,
+    pub heights: Range<f32>,
+    pub veins: f32,
+    pub length: u32,
+    pub biomes: Vec<Biome>,
+}
+
+pub type OreTable = Vec<OreRule>;
+
+pub fn ores_from_csv_reader(reader: impl Read) -> Result<OreTable> {
+    let mut res = Vec::new();
+    let mut reader = csv::Reader::from_reader(reader);
+    for record in reader.records() {
+        let record = record?;
+        let Ok(ore) = Block::from_str(&record[0]) else {
+            bail!("Unknown ore '{}'", &record[0]);
+        };
+        let mut biomes = Vec::new();
+        for biome in record[4]
+            .split(';')
+            .map(str::trim)
+            .filter(|b| !b.is_empty())
+        {
+            let Ok(biome) = Biome::from_str(biome) else {
+                bail!("Unknown biome '{}' for {}", biome, ore);
+            };
+            biomes.push(biome);
+        }
+        res.push(OreRule {
+            ore,
+            heights: range_from_str(&record[1])?,
+            veins: record[2].trim().parse()?,
+            length: record[3].trim().parse::<u32>()?.min(MAX_VEIN_LENGTH),
+            biomes,
+        });
+    }
+    Ok(res)
+}
+
+pub fn load_ore_table() -> OreTable {
+    ores_from_csv_reader(include_str!("../../assets/gen/ores.csv").as_bytes())
+        .expect("assets/gen/ores.csv is malformed")
+}
+
+fn unit(rng: usize) -> f32 {
+    (rng & 0xFFFF) as f32 / 65536.
+}
+
+fn random_dir(rng: usize) -> Vec3 {
+    Vec3::new(
+        unit(rng) * 2. - 1.,
+        (unit(rng >> 16) * 2. - 1.) * 0.5,
+        unit(rng >> 32) * 2. - 1.,
+    )
+    .normalize_or(Vec3::X)
+}
+
+/// Seeded random walk of a vein, as the centers of its blobs
+fn vein_path(
+    earth: &Earth,
+    rule: &OreRule,
+    key: BlockPos,
+    source: ColPos,
+) -> Option<Vec<BlockPos>> {
+    let rng = key.prng(earth.seed() ^ ORE_SALT);
+    let x = source.x * CHUNK_S1I + (rng % CHUNK_S1I as usize) as i32;
+    let z = source.z * CHUNK_S1I + ((rng >> 8) % CHUNK_S1I as usize) as i32;
+    if !rule.biomes.is_empty() {
+        let biome = earth.biome(x, z, &earth.ground(x, z));
+        if !rule.biomes.contains(&biome) {
+            return None;
+        }
+    }
+    let span = rule.heights.end - rule.heights.start;
+    let y = rule.heights.start + unit(rng >> 16) * span;
+    let mut pos = Vec3::new(x as f32, y, z as f32);
+    let mut dir = random_dir(rng >> 24);
+    let mut path = Vec::with_capacity(rule.length as usize);
+    for step in 0..rule.length {
+        path.push(BlockPos {
+            x: pos.x.floor() as i32,
+            y: pos.y.floor() as i32,
+            z: pos.z.floor() as i32,
+        });
+        let turn = random_dir((key + (0, step as i32 + 1, 0)).prng(earth.seed() ^ ORE_SALT));
+        dir = (dir + turn * 0.6).normalize_or(dir);
+        pos += dir * VEIN_STEP;
+    }
+    Some(path)
+}
+
+/// Ore blocks of the veins that reach into the column, whichever column they start in.
+/// Ore only replaces stone, which goes up to `stone_top` (inclusive).
+pub fn ore_runs(
+    earth: &Earth,
+    table: &OreTable,
+    col_pos: ColPos,
+    stone_top: impl Fn(ColedPos) -> i32,
+) -> Vec<Run> {
+    let (x0, z0) = (col_pos.x * CHUNK_S1I, col_pos.z * CHUNK_S1I);
+    let mut runs = Vec::new();
+    for (dx, dz) in iproduct!(-1..=1, -1..=1) {
+        let source = ColPos {
+            x: col_pos.x + dx,
+            z: col_pos.z + dz,
+        };
+        for (i, rule) in table.iter().enumerate() {
+            let rng = BlockPos2d {
+                x: source.x,
+                z: source.z,
+            }
+            .prng(earth.seed() ^ ORE_SALT ^ i as i32);
+            let count = rule.veins as usize + (unit(rng) < rule.veins.fract()) as usize;
+            for vein in 0..count {
+                // the step is added to y to get each turn of the walk
+                let key = BlockPos {
+                    x: source.x,
+                    y: ((i * 1024 + vein) << 8) as i32,
+                    z: source.z,
+                };
+                let Some(path) = vein_path(earth, rule, key, source) else {
+                    continue;
+                };
+                for (center, (bx, by, bz)) in iproduct!(path, iproduct!(-1..=1, -1..=1, -1..=1)) {
+                    if bx * bx + by * by + bz * bz > BLOB_RADIUS2 {
+                        continue;
+                    }
+                    let (x, y, z) = (center.x + bx - x0, center.y + by, center.z + bz - z0);
+                    if !(0..CHUNK_S1I).contains(&x) || !(0..CHUNK_S1I).contains(&z) || y < 0 {
+                        continue;
+                    }
+                    let pos2d = (x as usize, z as usize);
+                    if y <= stone_top(pos2d) {
+                        runs.push((pos2d, y, y, rule.ore));
+                    }
+                }
+            }
+        }
+    }
+    runs
+}
@@ -0,0 +1,554 @@
+use super::{generator::WorldGenerator, terrain_gen::GenerationState};
+use crate::{
+    block::Block,
+    world::{range_around, ColPos, ColedPos, VoxelWorld},
+};
+use bevy::tasks::{block_on, AsyncComputeTaskPool, Task};
+use itertools::iproduct;
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::Arc,
+};
+
+/// Vertical run of blocks in a column: (x, z), bottom, top (inclusive), block
+pub type Run = (ColedPos, i32, i32, Block);
+
+/// Stages of the generation of a column, in order.
+/// A column is only finalized once the columns its neighbours' features can spill from
+/// (see `WorldGenerator::spill_radius`) are done with their features.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default, Hash)]
+pub enum GenStage {
+    /// ground, soil and water
+    #[default]
+    Terrain,
+    /// caves and rivers
+    Carving,
+    /// trees and everything else that can spill into the neighbours
+    Features,
+    /// applies what the neighbours spilled and marks the chunks as loaded
+    Finalize,
+}
+
+impl GenStage {
+    pub fn next(self) -> Option<Self> {
+        match self {
+            GenStage::Terrain => Some(GenStage::Carving),
+            GenStage::Carving => Some(GenStage::Features),
+            GenStage::Features => Some(GenStage::Finalize),
+            GenStage::Finalize => None,
+        }
+    }
+}
+
+/// How spilled runs are applied, every canopy goes before any solid
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+pub enum SpillLayer {
+    /// only above the ground of the target column (leaves)
+    Canopy,
+    /// over anything (trunks)
+    Solid,
+}
+
+/// Runs that a column's features put in another column (or itself)
+#[derive(Clone)]
+pub struct Spill {
+    pub target: ColPos,
+    pub layer: SpillLayer,
+    pub runs: Vec<Run>,
+}
+
+struct ColGen {
+    done: Option<GenStage>,
+    target: GenStage,
+    priority: u32,
+    // a neighbour got reloaded and needs this column's spills again
+    respill: bool,
+    state: GenerationState,
+    // the column is built here and only moved to the world once finalized
+    staging: Arc<VoxelWorld>,
+    // tells a column apart from one that was dropped and requested again
+    id: u64,
+    // a stage of this column is running as a task
+    running: bool,
+    // key of the column in the ready queue, if it's in there
+    queued: Option<ReadyKey>,
+}
+
+/// (priority, stage, x, z) of a column that can be processed, the smallest is the most urgent
+type ReadyKey = (u32, GenStage, i32, i32);
+
+/// A stage of a column running on the async compute pool, gives the state back once done
+struct StageTask {
+    col_pos: ColPos,
+    id: u64,
+    stage: GenStage,
+    task: Task<GenerationState>,
+}
+
+/// The columns at most `radius` away from col_pos, col_pos excluded
+fn neighbours(col_pos: ColPos, radius: i32) -> impl Iterator<Item = ColPos> {
+    iproduct!(
+        range_around(col_pos.x, radius),
+        range_around(col_pos.z, radius)
+    )
+    .map(|(x, z)| ColPos { x, z })
+    .filter(move |pos| *pos != col_pos)
+}
+
+/// Schedules the generation stages of every column and buffers what spills between them,
+/// so the result doesn't depend on the order columns are requested in.
+/// Stages of different columns can run at the same time since each column is built in
+/// a world of its own, the world only gets whole columns.
+pub struct GenPipeline {
+    cols: HashMap<ColPos, ColGen>,
+    // spilled runs waiting for their target to be finalized, by target then (layer, source)
+    spills: HashMap<ColPos, HashMap<(SpillLayer, ColPos), Vec<Run>>>,
+    // columns that can be processed right now, kept up to date as the columns progress
+    ready: BTreeSet<ReadyKey>,
+    // requested columns that aren't finalized yet
+    unfinished: HashSet<ColPos>,
+    // how far the features of a column can spill, from the generator
+    spill_radius: i32,
+    tasks: Vec<StageTask>,
+    next_id: u64,
+}
+
+impl GenPipeline {
+    pub fn new(spill_radius: i32) -> Self {
+        GenPipeline {
+            cols: HashMap::new(),
+            spills: HashMap::new(),
+            ready: BTreeSet::new(),
+            unfinished: HashSet::new(),
+            spill_radius,
+            tasks: Vec::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn for_generator(generator: &dyn WorldGenerator) -> Self {
+        Self::new(generator.spill_radius())
+    }
+
+    /// Asks for a column to be fully generated, along with whatever its neighbours need
+    pub fn request(&mut self, col_pos: ColPos, priority: u32) {
+        self.require(col_pos, GenStage::Finalize, priority);
+    }
+
+    fn require(&mut self, col_pos: ColPos, stage: GenStage, priority: u32) {
+        let mut is_new = false;
+        let id = self.next_id;
+        let col = self.cols.entry(col_pos).or_insert_with(|| {
+            is_new = true;
+            ColGen {
+                done: None,
+                target: stage,
+                priority,
+                respill: false,
+                state: GenerationState::new(col_pos),
+                staging: Arc::new(VoxelWorld::new()),
+                id,
+                running: false,
+                queued: None,
+            }
+        });
+        if is_new {
+            self.next_id += 1;
+        }
+        if !is_new && col.target >= stage && col.priority <= priority {
+            return;
+        }
+        col.target = col.target.max(stage);
+        col.priority = col.priority.min(priority);
+        if stage == GenStage::Finalize && col.done != Some(GenStage::Finalize) {
+            self.unfinished.insert(col_pos);
+        }
+        if stage == GenStage::Finalize {
+            for neighbour in neighbours(col_pos, self.spill_radius) {
+                self.require(neighbour, GenStage::Features, priority);
+            }
+        }
+        // a new column can hold back the finalization of the ones around it
+        if is_new {
+            self.refresh_around(col_pos);
+        } else {
+            self.refresh(col_pos);
+        }
+    }
+
+    fn ready_stage(&self, col_pos: ColPos, col: &ColGen) -> Option<GenStage> {
+        if col.running {
+            return None;
+        }
+        let stage = if col.respill {
+            GenStage::Features
+        } else {
+            match col.done {
+                None => GenStage::Terrain,
+                Some(done) if done < col.target => done.next()?,
+                _ => return None,
+            }
+        };
+        if stage != GenStage::Finalize {
+            return Some(stage);
+        }
+        neighbours(col_pos, self.spill_radius)
+            .all(|pos| {
+                self.cols.get(&pos).is_some_and(|neighbour| {
+                    neighbour.done >= Some(GenStage::Features) && !neighbour.respill
+                })
+            })
+            .then_some(stage)
+    }
+
+    /// Puts the column in the ready queue (or takes it out) according to its current state
+    fn refresh(&mut self, col_pos: ColPos) {
+        let Some(col) = self.cols.get(&col_pos) else {
+            return;
+        };
+        let key = self
+            .ready_stage(col_pos, col)
+            .map(|stage| (col.priority, stage, col_pos.x, col_pos.z));
+        let old = std::mem::replace(&mut self.cols.get_mut(&col_pos).unwrap().queued, key);
+        if old == key {
+            return;
+        }
+        if let Some(old) = old {
+            self.ready.remove(&old);
+        }
+        if let Some(key) = key {
+            self.ready.insert(key);
+        }
+    }
+
+    /// Refreshes the column and the ones whose finalization can wait on it
+    fn refresh_around(&mut self, col_pos: ColPos) {
+        self.refresh(col_pos);
+        for neighbour in neighbours(col_pos, self.spill_radius) {
+            self.refresh(neighbour);
+        }
+    }
+
+    /// The most urgent (column, stage) that can be processed right now
+    pub fn next_task(&self) -> Option<(ColPos, GenStage)> {
+        self.ready
+            .first()
+            .map(|&(_, stage, x, z)| (ColPos { x, z }, stage))
+    }
+
+    /// Requested columns that aren't finalized yet
+    pub fn pending(&self) -> usize {
+        self.unfinished.len()
+    }
+
+    /// Every column the pipeline holds, finalized or not
+    pub fn cols(&self) -> impl Iterator<Item = ColPos> + '_ {
+        self.cols.keys().copied()
+    }
+
+    pub fn is_finalized(&self, col_pos: ColPos) -> bool {
+        self.cols
+            .get(&col_pos)
+            .is_some_and(|col| col.done == Some(GenStage::Finalize))
+    }
+
+    fn begin(&mut self, col_pos: ColPos, stage: GenStage) {
+        if stage != GenStage::Finalize {
+            return;
+        }
+        let mut spilled: Vec<_> = self
+            .spills
+            .remove(&col_pos)
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        spilled.sort_by_key(|((layer, source), _)| (*layer, source.x, source.z));
+        if let Some(col) = self.cols.get_mut(&col_pos) {
+            col.state.spilled = spilled
+                .into_iter()
+                .map(|((layer, _), runs)| (layer, runs))
+                .collect();
+        }
+    }
+
+    fn complete(&mut self, col_pos: ColPos, stage: GenStage, world: &VoxelWorld) {
+        let Some(col) = self.cols.get_mut(&col_pos) else {
+            return;
+        };
+        let spills = std::mem::take(&mut col.state.spills);
+        if stage == GenStage::Features && col.respill {
+            col.respill = false;
+        } else {
+            col.done = Some(stage);
+        }
+        if stage == GenStage::Finalize {
+            self.unfinished.remove(&col_pos);
+            // nothing needs the heights of a finished column anymore, only its trees to spill them again
+            col.state = GenerationState {
+                trees: std::mem::take(&mut col.state.trees),
+                ..GenerationState::new(col_pos)
+            };
+            world.insert_col(col_pos, &col.staging);
+        }
+        for spill in spills {
+            // finalized columns already got this the first time around
+            if spill.runs.is_empty() || self.is_finalized(spill.target) {
+                continue;
+            }
+            self.spills
+                .entry(spill.target)
+                .or_default()
+                .insert((spill.layer, col_pos), spill.runs);
+        }
+        self.refresh_around(col_pos);
+    }
+
+    fn remove(&mut self, col_pos: ColPos) -> bool {
+        let Some(col) = self.cols.remove(&col_pos) else {
+            return false;
+        };
+        if let Some(key) = col.queued {
+            self.ready.remove(&key);
+        }
+        self.unfinished.remove(&col_pos);
+        self.spills.remove(&col_pos);
+        // the columns that spilled in it will have to do it again if it comes back
+        for neighbour in neighbours(col_pos, self.spill_radius) {
+            if let Some(col) = self.cols.get_mut(&neighbour) {
+                if col.done >= Some(GenStage::Features) {
+                    col.respill = true;
+                }
+            }
+            self.refresh(neighbour);
+        }
+        true
+    }
+
+    fn is_needed(&self, col_pos: ColPos) -> bool {
+        iproduct!(
+            range_around(col_pos.x, self.spill_radius),
+            range_around(col_pos.z, self.spill_radius)
+        )
+        .any(|(x, z)| {
+            self.cols
+                .get(&ColPos { x, z })
+                .is_some_and(|col| col.target == GenStage::Finalize)
+        })
+    }
+
+    /// Drops an unloaded column, along with the partially generated columns around it
+    /// that nothing needs anymore. It starts over up to its features if a neighbour needs them.
+    pub fn forget(&mut self, col_pos: ColPos) {
+        if !self.remove(col_pos) {
+            return;
+        }
+        // a neighbour that isn't finalized yet still needs its features
+        let needed_by = neighbours(col_pos, self.spill_radius)
+            .filter(|pos| self.unfinished.contains(pos))
+            .filter_map(|pos| self.cols.get(&pos).map(|col| col.priority))
+            .min();
+        if let Some(priority) = needed_by {
+            self.require(col_pos, GenStage::Features, priority);
+        }
+        for (x, z) in iproduct!(
+            range_around(col_pos.x, self.spill_radius),
+            range_around(col_pos.z, self.spill_radius)
+        ) {
+            let pos = ColPos { x, z };
+            let Some(col) = self.cols.get(&pos) else {
+                continue;
+            };
+            if col.target == GenStage::Finalize || self.is_needed(pos) {
+                continue;
+            }
+            self.remove(pos);
+        }
+    }
+
+    /// Starts the most urgent stages that can run, as tasks on the async compute pool,
+    /// until max_tasks are running
+    pub fn spawn_tasks(&mut self, generator: &Arc<dyn WorldGenerator>, max_tasks: usize) {
+        let pool = AsyncComputeTaskPool::get();
+        while self.tasks.len() < max_tasks {
+            let Some((col_pos, stage)) = self.next_task() else {
+                return;
+            };
+            self.begin(col_pos, stage);
+            let Some(col) = self.cols.get_mut(&col_pos) else {
+                return;
+            };
+            col.running = true;
+            let id = col.id;
+            let mut state = std::mem::take(&mut col.state);
+            let staging = col.staging.clone();
+            let generator = generator.clone();
+            let task = pool.spawn(async move {
+                generator.process_stage(&mut state, stage, &staging);
+                state
+            });
+            self.tasks.push(StageTask {
+                col_pos,
+                id,
+                stage,
+                task,
+            });
+            self.refresh(col_pos);
+        }
+    }
+
+    /// Completes the stages whose task is done, finalized columns are moved to the world
+    pub fn poll_tasks(&mut self, world: &VoxelWorld) {
+        let (finished, running): (Vec<_>, Vec<_>) = std::mem::take(&mut self.tasks)
+            .into_iter()
+            .partition(|task| task.task.is_finished());
+        self.tasks = running;
+        for StageTask {
+            col_pos,
+            id,
+            stage,
+            task,
+        } in finished
+        {
+            let state = block_on(task);
+            // the column was dropped (and maybe requested again) while the stage ran
+            let Some(col) = self.cols.get_mut(&col_pos).filter(|col| col.id == id) else {
+                continue;
+            };
+            col.state = state;
+            col.running = false;
+            self.complete(col_pos, stage, world);
+        }
+    }
+
+    /// Runs every stage that's left on this thread
+    pub fn run(&mut self, generator: &dyn WorldGenerator, world: &VoxelWorld) {
+        while let Some((col_pos, stage)) = self.next_task() {
+            self.begin(col_pos, stage);
+            let Some(col) = self.cols.get_mut(&col_pos) else {
+                continue;
+            };
+            generator.process_stage(&mut col.state, stage, &col.staging);
+            self.complete(col_pos, stage, world);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+
+    /// Records the stages it's asked to process, in order
+    struct Recorder {
+        spill_radius: i32,
+        log: Mutex<Vec<(ColPos, GenStage)>>,
+    }
+
+    impl Recorder {
+        fn new(spill_radius: i32) -> Self {
+            Recorder {
+                spill_radius,
+                log: Mutex::new(Vec::new()),
+            }
+        }
+
+        fn when(&self, col_pos: ColPos, stage: GenStage) -> Option<usize> {
+            self.log
+                .lock()
+                .iter()
+                .position(|done| *done == (col_pos, stage))
+        }
+    }
+
+    impl WorldGenerator for Recorder {
+        fn seed(&self) -> i32 {
+            0
+        }
+
+        fn spill_radius(&self) -> i32 {
+            self.spill_radius
+        }
+
+        fn process_stage(&self, state: &mut GenerationState, stage: GenStage, _world: &VoxelWorld) {
+            self.log.lock().push((state.col_pos, stage));
+        }
+    }
+
+    fn run(pipeline: &mut GenPipeline, generator: &Recorder) {
+        let world = VoxelWorld::new();
+        pipeline.run(generator, &world);
+    }
+
+    #[test]
+    fn finalize_waits_for_the_features_around() {
+        let generator = Recorder::new(1);
+        let mut pipeline = GenPipeline::for_generator(&generator);
+        let center = ColPos { x: 2, z: -1 };
+        pipeline.request(center, 0);
+        assert_eq!(pipeline.pending(), 1);
+        run(&mut pipeline, &generator);
+        assert!(pipeline.is_finalized(center) && pipeline.pending() == 0);
+        let finalized = generator.when(center, GenStage::Finalize).unwrap();
+        for neighbour in neighbours(center, 1) {
+            assert!(generator.when(neighbour, GenStage::Features).unwrap() < finalized);
+            // only there to spill in the center
+            assert_eq!(generator.when(neighbour, GenStage::Finalize), None);
+        }
+        // nothing further than the spill radius is generated
+        assert_eq!(generator.log.lock().len(), 4 + 8 * 3);
+    }
+
+    #[test]
+    fn stages_of_a_column_run_in_order() {
+        let generator = Recorder::new(0);
+        let mut pipeline = GenPipeline::for_generator(&generator);
+        pipeline.request(ColPos::default(), 0);
+        run(&mut pipeline, &generator);
+        let stages: Vec<GenStage> = generator
+            .log
+            .lock()
+            .iter()
+            .map(|(_, stage)| *stage)
+            .collect();
+        assert_eq!(
+            stages,
+            [
+                GenStage::Terrain,
+                GenStage::Carving,
+                GenStage::Features,
+                GenStage::Finalize
+            ]
+        );
+    }
+
+    #[test]
+    fn most_urgent_column_goes_first() {
+        let generator = Recorder::new(0);
+        let mut pipeline = GenPipeline::for_generator(&generator);
+        let (far, near) = (ColPos { x: 10, z: 0 }, ColPos { x: 1, z: 0 });
+        pipeline.request(far, 5);
+        pipeline.request(near, 1);
+        assert_eq!(pipeline.next_task(), Some((near, GenStage::Terrain)));
+        // requesting it again with a better priority moves it up
+        pipeline.request(far, 0);
+        assert_eq!(pipeline.next_task(), Some((far, GenStage::Terrain)));
+    }
+
+    #[test]
+    fn forget_drops_the_columns_only_it_needed() {
+        let generator = Recorder::new(1);
+        let mut pipeline = GenPipeline::for_generator(&generator);
+        let (a, b) = (ColPos { x: 0, z: 0 }, ColPos { x: 1, z: 0 });
+        pipeline.request(a, 0);
+        pipeline.request(b, 0);
+        pipeline.forget(a);
+        // the columns around b are still needed, the others around a aren't
+        assert!(pipeline.cols.contains_key(&ColPos { x: 0, z: 1 }));
+        assert!(!pipeline.cols.contains_key(&ColPos { x: -1, z: 0 }));
+        assert_eq!(pipeline.pending(), 1);
+        // a is generated again for b, up to its features only
+        run(&mut pipeline, &generator);
+        assert!(pipeline.is_finalized(b) && !pipeline.is_finalized(a));
+        assert_eq!(pipeline.cols[&a].done, Some(GenStage::Features));
+    }
+}
@@ -0,0 +1,311 @@
+use super::{
+    config::GenConfig, generator::WorldGenerator, pipeline::GenPipeline, presets::WorldPreset,
+    terrain_gen::WorldSeed,
+};
+use crate::world::{pos2d::chunks_in_col, ColPos, VoxelWorld};
+use anyhow::{bail, Context, Result};
+use itertools::iproduct;
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+// columns are generated by square tiles of this many columns per side, one tile per thread.
+// Each tile also generates the support columns around it, so bigger tiles waste less work
+// but take more memory.
+const PREGEN_TILE: i32 = 12;
+const REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What `riverbed pregen` generates. Usage:
+/// `riverbed pregen [--radius N] [--center X,Z] [--seed S] [--preset P] [--out DIR] [--threads N]`,
+/// with the radius and center in columns. Nothing is saved without `--out`.
+pub struct PregenOptions {
+    pub center: ColPos,
+    pub radius: i32,
+    pub seed: i32,
+    pub preset: WorldPreset,
+    pub out: Option<PathBuf>,
+    pub threads: usize,
+}
+
+impl PregenOptions {
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        let mut options = PregenOptions {
+            center: ColPos { x: 0, z: 0 },
+            radius: 16,
+            seed: WorldSeed::default().0,
+            preset: WorldPreset::default(),
+            out: None,
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(value) = args.next() else {
+                bail!("Missing a value after {}", arg);
+            };
+            match arg.as_str() {
+                "--radius" => options.radius = value.parse()?,
+                "--center" => {
+                    let Some((x, z)) = value.split_once(',') else {
+                        bail!("Expected --center X,Z, got '{}'", value);
+                    };
+                    options.center = ColPos {
+                        x: x.trim().parse()?,
+                        z: z.trim().parse()?,
+                    };
+                }
+                "--seed" => options.seed = value.parse()?,
+                "--preset" => options.preset = value.parse()?,
+                "--out" => options.out = Some(PathBuf::from(value)),
+                "--threads" => options.threads = value.parse::<usize>()?.max(1),
+                _ => bail!("Unknown option {}", arg),
+            }
+        }
+        Ok(options)
+    }
+
+    /// Columns within the radius
+    fn cols(&self) -> impl Iterator<Item = ColPos> + '_ {
+        let r = self.radius;
+        iproduct!(-r..=r, -r..=r)
+            .filter(move |(dx, dz)| dx * dx + dz * dz <= r * r)
+            .map(|(dx, dz)| ColPos {
+                x: self.center.x + dx,
+                z: self.center.z + dz,
+            })
+    }
+}
+
+/// Groups the columns by square tiles of PREGEN_TILE columns per side
+pub fn tiles(cols: impl IntoIterator<Item = ColPos>) -> Vec<Vec<ColPos>> {
+    let mut tiles: BTreeMap<(i32, i32), Vec<ColPos>> = BTreeMap::new();
+    for col in cols {
+        tiles
+            .entry((col.x.div_euclid(PREGEN_TILE), col.z.div_euclid(PREGEN_TILE)))
+            .or_default()
+            .push(col);
+    }
+    tiles.into_values().collect()
+}
+
+fn save_col(world: &VoxelWorld, col_pos: ColPos, out: &Path) -> Result<()> {
+    for chunk_pos in chunks_in_col(&col_pos) {
+        let Some(bytes) = world.save_chunk(chunk_pos) else {
+            continue;
+        };
+        let path = out.join(format!(
+            "{}_{}_{}.chunk",
+            chunk_pos.x, chunk_pos.y, chunk_pos.z
+        ));
+        std::fs::write(&path, bytes).with_context(|| format!("Can't write {:?}", path))?;
+    }
+    Ok(())
+}
+
+/// Generates a tile of columns in a world of its own, handed to `on_tile` once done
+fn generate_tile(
+    generator: &dyn WorldGenerator,
+    cols: &[ColPos],
+    on_tile: &(impl Fn(&VoxelWorld, &[ColPos]) -> Result<()> + Sync),
+    done: &AtomicUsize,
+) -> Result<()> {
+    let world = VoxelWorld::new();
+    let mut pipeline = GenPipeline::for_generator(generator);
+    for (i, col_pos) in cols.iter().enumerate() {
+        pipeline.request(*col_pos, i as u32);
+    }
+    pipeline.run(generator, &world);
+    on_tile(&world, cols)?;
+    done.fetch_add(cols.len(), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Hands out the tiles, keeping track of the ones being generated
+#[derive(Default)]
+struct TileQueue {
+    next: usize,
+    working: BTreeSet<usize>,
+}
+
+impl TileQueue {
+    fn take(&mut self, count: usize) -> Option<usize> {
+        let i = self.next;
+        if i >= count {
+            return None;
+        }
+        self.next += 1;
+        self.working.insert(i);
+        Some(i)
+    }
+
+    /// Marks the tile done, returns the columns the generator's caches are still needed for
+    fn finish(&mut self, i: usize, tiles: &[Vec<ColPos>]) -> Vec<ColPos> {
+        self.working.remove(&i);
+        // the next tile is a neighbour of the last ones, so it can reuse some of their caches
+        self.working
+            .iter()
+            .copied()
+            .chain(Some(self.next))
+            .filter_map(|i| tiles.get(i))
+            .flatten()
+            .copied()
+            .collect()
+    }
+}
+
+/// Generates the tiles on all threads, reporting the progress as it goes.
+/// Since generation doesn't depend on the order, tiles give the same blocks as the game would.
+pub fn generate_tiles(
+    generator: &dyn WorldGenerator,
+    tiles: &[Vec<ColPos>],
+    threads: usize,
+    on_tile: impl Fn(&VoxelWorld, &[ColPos]) -> Result<()> + Sync,
+) -> Result<()> {
+    let total: usize = tiles.iter().map(Vec::len).sum();
+    let (queue, done) = (Mutex::new(TileQueue::default()), AtomicUsize::new(0));
+    let running = AtomicUsize::new(threads);
+    let (start, main) = (Instant::now(), std::thread::current());
+    let results: Vec<Result<()>> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| -> Result<()> {
+                    let generate = || -> Result<()> {
+                        loop {
+                            let Some(i) = queue.lock().take(tiles.len()) else {
+                                break;
+                            };
+                            generate_tile(generator, &tiles[i], &on_tile, &done)?;
+                            // or the caches would grow with the whole area
+                            let needed = queue.lock().finish(i, tiles);
+                            generator.trim_caches(&needed);
+                        }
+                        Ok(())
+                    };
+                    let res = generate();
+                    // wakes the report loop up so it doesn't wait out its interval once all are done
+                    running.fetch_sub(1, Ordering::Release);
+                    main.unpark();
+                    res
+                })
+            })
+            .collect();
+        loop {
+            std::thread::park_timeout(REPORT_INTERVAL);
+            // a panicking worker never gets to count itself out
+            if running.load(Ordering::Acquire) == 0 || workers.iter().all(|w| w.is_finished()) {
+                break;
+            }
+            let (done, secs) = (done.load(Ordering::Relaxed), start.elapsed().as_secs_f32());
+            println!(
+                "{}/{} columns ({:.0}%), {:.1} columns/s",
+                done,
+                total,
+                100. * done as f32 / total.max(1) as f32,
+                done as f32 / secs
+            );
+        }
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("a generation thread panicked"))
+            .collect()
+    });
+    results.into_iter().collect::<Result<()>>()?;
+    let secs = start.elapsed().as_secs_f32();
+    println!(
+        "Generated {} columns in {:.1}s ({:.1} columns/s)",
+        total,
+        secs,
+        total as f32 / secs
+    );
+    Ok(())
+}
+
+/// Generates every column within the radius, saving them if asked to
+pub fn pregen(options: &PregenOptions) -> Result<()> {
+    if let Some(out) = &options.out {
+        std::fs::create_dir_all(out).with_context(|| format!("Can't create {:?}", out))?;
+    }
+    let config = GenConfig::from_disk()?;
+    let generator = options.preset.build(options.seed, &config);
+    let tiles = tiles(options.cols());
+    let total: usize = tiles.iter().map(Vec::len).sum();
+    println!(
+        "Generating {} columns around {:?} ({:?} world, seed {}) on {} threads",
+        total, options.center, options.preset, options.seed, options.threads
+    );
+    generate_tiles(
+        generator.as_ref(),
+        &tiles,
+        options.threads,
+        |world, cols| {
+            if let Some(out) = &options.out {
+                for col_pos in cols {
+                    save_col(world, *col_pos, out)?;
+                }
+            }
+            Ok(())
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn options_from_args() {
+        let options = PregenOptions::from_args(&args(
+            "--radius 3 --center -4,7 --seed 12 --preset superflat --out maps --threads 0",
+        ))
+        .unwrap();
+        assert_eq!(options.radius, 3);
+        assert_eq!(options.center, ColPos { x: -4, z: 7 });
+        assert_eq!(options.seed, 12);
+        assert!(matches!(options.preset, WorldPreset::Superflat(_)));
+        assert_eq!(options.out, Some(PathBuf::from("maps")));
+        assert_eq!(options.threads, 1);
+        // a circle of radius 3
+        assert_eq!(options.cols().count(), 29);
+        assert!(options
+            .cols()
+            .all(|col| (col.x + 4).pow(2) + (col.z - 7).pow(2) <= 9));
+    }
+
+    #[test]
+    fn bad_args() {
+        for bad in [
+            "--radius",
+            "--radius ten",
+            "--center 4",
+            "--center 4,z",
+            "--preset moon",
+            "--size 3",
+        ] {
+            assert!(PregenOptions::from_args(&args(bad)).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn trims_to_the_tiles_in_progress() {
+        let tiles = tiles(iproduct!(0..PREGEN_TILE * 4, 0..1).map(|(x, z)| ColPos { x, z }));
+        let mut queue = TileQueue::default();
+        let (a, b) = (queue.take(tiles.len()), queue.take(tiles.len()));
+        assert_eq!((a, b), (Some(0), Some(1)));
+        // tile 1 is still being generated and tile 2 is next
+        let needed = queue.finish(0, &tiles);
+        assert_eq!(needed, [tiles[1].clone(), tiles[2].clone()].concat());
+        queue.take(tiles.len());
+        queue.take(tiles.len());
+        assert_eq!(queue.take(tiles.len()), None);
+        queue.finish(1, &tiles);
+        queue.finish(2, &tiles);
+        assert_eq!(queue.finish(3, &tiles), []);
+    }
+}
@@ -0,0 +1,226 @@
+use super::{
+    biomes::Biome,
+    config::GenConfig,
+    earth_gen::Earth,
+    generator::{mark_loaded, WorldGenerator},
+    heightmap_gen::{HeightmapGen, HeightmapSettings},
+    pipeline::{GenStage, Run},
+    terrain_gen::GenerationState,
+};
+use crate::{
+    block::Block,
+    world::{VoxelWorld, CHUNK_S1, CHUNK_S1I, CHUNK_S2},
+};
+use anyhow::{bail, Result};
+use bevy::prelude::*;
+use itertools::iproduct;
+use std::str::FromStr;
+use strum::IntoEnumIterator;
+
+// blocks of the debug world are cubes of this size, this far apart, on a floor at DEBUG_Y
+const DEBUG_CUBE: i32 = 4;
+const DEBUG_SPACING: i32 = 8;
+const DEBUG_Y: i32 = 64;
+
+/// Generator a world is created with. Can be overridden with the `RIVERBED_PRESET`
+/// environment variable: `earth`, `void`, `debug`, `biome:<Biome>`, `superflat`,
+/// `superflat:<Block>*<thickness>,...` (layers from the bottom up)
+/// or `heightmap:<settings>` (see `HeightmapSettings`).
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub enum WorldPreset {
+    Earth,
+    Superflat(Vec<(Block, i32)>),
+    Void,
+    SingleBiome(Biome),
+    Debug,
+    Heightmap(HeightmapSettings),
+}
+
+impl Default for WorldPreset {
+    fn default() -> Self {
+        std::env::var("RIVERBED_PRESET")
+            .ok()
+            .and_then(|preset| match preset.parse() {
+                Ok(preset) => Some(preset),
+                Err(err) => {
+                    warn!("Ignoring RIVERBED_PRESET: {}", err);
+                    None
+                }
+            })
+            .unwrap_or(WorldPreset::Earth)
+    }
+}
+
+impl FromStr for WorldPreset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, args) = s.trim().split_once(':').unwrap_or((s.trim(), ""));
+        Ok(match name {
+            "earth" => WorldPreset::Earth,
+            "void" => WorldPreset::Void,
+            "debug" => WorldPreset::Debug,
+            "biome" => {
+                let Ok(biome) = Biome::from_str(args.trim()) else {
+                    bail!("Unknown biome '{}'", args);
+                };
+                WorldPreset::SingleBiome(biome)
+            }
+            "superflat" if args.trim().is_empty() => WorldPreset::Superflat(default_layers()),
+            "superflat" => WorldPreset::Superflat(layers_from_str(args)?),
+            "heightmap" => WorldPreset::Heightmap(args.parse()?),
+            _ => bail!("Unknown world preset '{}'", name),
+        })
+    }
+}
+
+impl WorldPreset {
+    pub fn build(&self, seed: i32, config: &GenConfig) -> Box<dyn WorldGenerator> {
+        match self {
+            WorldPreset::Earth => Box::new(Earth::new(seed, config)),
+            WorldPreset::Superflat(layers) => Box::new(Superflat::new(seed, layers.clone())),
+            WorldPreset::Void => Box::new(Void { seed }),
+            WorldPreset::SingleBiome(biome) => {
+                Box::new(Earth::new(seed, config).with_biome(*biome))
+            }
+            WorldPreset::Debug => Box::new(DebugGrid { seed }),
+            WorldPreset::Heightmap(settings) => {
+                match HeightmapGen::new(seed, settings.clone(), config) {
+                    Ok(generator) => Box::new(generator),
+                    Err(err) => {
+                        error!("Falling back to an empty world: {:#}", err);
+                        Box::new(Void { seed })
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn default_layers() -> Vec<(Block, i32)> {
+    vec![(Block::Stone, 56), (Block::Dirt, 4), (Block::Grass, 1)]
+}
+
+/// Parses layers written as `Block*thickness` (or just `Block` for 1), separated by commas
+fn layers_from_str(s: &str) -> Result<Vec<(Block, i32)>> {
+    let mut layers = Vec::new();
+    for layer in s.split(',').map(str::trim).filter(|l| !l.is_empty()) {
+        let (block, thickness) = layer.split_once('*').unwrap_or((layer, "1"));
+        let Ok(block) = Block::from_str(block.trim()) else {
+            bail!("Unknown block '{}'", block);
+        };
+        let thickness: i32 = thickness.trim().parse()?;
+        if thickness < 1 {
+            bail!("Layer of {} must be at least 1 block thick", block);
+        }
+        layers.push((block, thickness));
+    }
+    Ok(layers)
+}
+
+/// Gives every x,z of the column the same biome, for the tints
+fn set_biome(world: &VoxelWorld, state: &GenerationState, biome: Biome) {
+    world
+        .biomes
+        .insert(state.col_pos, vec![biome; CHUNK_S2].into_boxed_slice());
+}
+
+/// The same layers of blocks everywhere
+pub struct Superflat {
+    seed: i32,
+    // bottom to top, with their thickness
+    layers: Vec<(Block, i32)>,
+}
+
+impl Superflat {
+    pub fn new(seed: i32, layers: Vec<(Block, i32)>) -> Self {
+        Superflat { seed, layers }
+    }
+}
+
+impl WorldGenerator for Superflat {
+    fn seed(&self) -> i32 {
+        self.seed
+    }
+
+    fn process_stage(&self, state: &mut GenerationState, stage: GenStage, world: &VoxelWorld) {
+        match stage {
+            GenStage::Terrain => {
+                let mut runs: Vec<Run> = Vec::with_capacity(CHUNK_S2 * self.layers.len());
+                let mut bottom = 0;
+                for (block, thickness) in self.layers.iter() {
+                    for pos in iproduct!(0..CHUNK_S1, 0..CHUNK_S1) {
+                        runs.push((pos, bottom, bottom + thickness - 1, *block));
+                    }
+                    bottom += thickness;
+                }
+                world.set_col_runs(state.col_pos, &runs);
+                set_biome(world, state, Biome::Plains);
+                state.top_height = Some(bottom - 1);
+            }
+            GenStage::Carving | GenStage::Features => {}
+            GenStage::Finalize => mark_loaded(state, world),
+        }
+    }
+}
+
+/// Nothing but air
+pub struct Void {
+    seed: i32,
+}
+
+impl WorldGenerator for Void {
+    fn seed(&self) -> i32 {
+        self.seed
+    }
+
+    fn process_stage(&self, state: &mut GenerationState, stage: GenStage, world: &VoxelWorld) {
+        if stage == GenStage::Finalize {
+            mark_loaded(state, world);
+        }
+    }
+}
+
+/// Every block laid out in a grid on a stone floor, to check how they look
+pub struct DebugGrid {
+    seed: i32,
+}
+
+impl WorldGenerator for DebugGrid {
+    fn seed(&self) -> i32 {
+        self.seed
+    }
+
+    fn process_stage(&self, state: &mut GenerationState, stage: GenStage, world: &VoxelWorld) {
+        match stage {
+            GenStage::Terrain => {
+                let mut runs: Vec<Run> = iproduct!(0..CHUNK_S1, 0..CHUNK_S1)
+                    .map(|pos| (pos, DEBUG_Y - 1, DEBUG_Y - 1, Block::Stone))
+                    .collect();
+                let blocks: Vec<Block> = Block::iter().filter(|b| *b != Block::Air).collect();
+                let row = (blocks.len() as f32).sqrt().ceil() as usize;
+                let (x0, z0) = (state.col_pos.x * CHUNK_S1I, state.col_pos.z * CHUNK_S1I);
+                for (i, block) in blocks.into_iter().enumerate() {
+                    let bx = (i % row) as i32 * DEBUG_SPACING;
+                    let bz = (i / row) as i32 * DEBUG_SPACING;
+                    for (dx, dz) in iproduct!(0..DEBUG_CUBE, 0..DEBUG_CUBE) {
+                        let (x, z) = (bx + dx - x0, bz + dz - z0);
+                        if (0..CHUNK_S1I).contains(&x) && (0..CHUNK_S1I).contains(&z) {
+                            runs.push((
+                                (x as usize, z as usize),
+                                DEBUG_Y,
+                                DEBUG_Y + DEBUG_CUBE - 1,
+                                block,
+                            ));
+                        }
+                    }
+                }
+                world.set_col_runs(state.col_pos, &runs);
+                set_biome(world, state, Biome::Plains);
+                state.top_height = Some(DEBUG_Y + DEBUG_CUBE - 1);
+            }
+            GenStage::Carving | GenStage::Features => {}
+            GenStage::Finalize => mark_loaded(state, world),
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use super::noise::DomainWarp;
+use crate::world::{BlockPos2d, WATER_H};
+use bevy::math::Vec2;
+use dashmap::DashMap;
+use itertools::iproduct;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+// rivers flow between the (jittered) centers of cells of this size, in blocks
+const RIVER_CELL: i32 = 128;
+// segments are computed and cached by square regions of this many cells
+const REGION_CELLS: i32 = 8;
+// a river is at most this many cells long, which bounds how far to look for their sources
+const MAX_RIVER_CELLS: i32 = 16;
+const RIVER_SALT: i32 = 0x5712;
+// in percent of the cells high enough to be a source
+const SOURCE_CHANCE: usize = 12;
+const SOURCE_MIN_HEIGHT: i32 = WATER_H + 40;
+// widths in blocks, rivers get wider with every cell downstream
+const SOURCE_WIDTH: f32 = 8.;
+const WIDTH_GROWTH: f32 = 3.;
+// banks slope down to the water over this distance
+const BANK: f32 = 12.;
+// how far from the water the ground is moister
+const WET_RANGE: f32 = 64.;
+// how far rivers wander off the straight line between cells
+const MEANDER: f32 = 24.;
+// furthest a segment can have an effect on the ground
+const REACH: f32 =
+    (SOURCE_WIDTH + WIDTH_GROWTH * MAX_RIVER_CELLS as f32) / 2. + BANK + WET_RANGE + MEANDER;
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Straight piece of river between two cell centers
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    a: Vec2,
+    b: Vec2,
+    // water level at a and b
+    level: (f32, f32),
+    width: (f32, f32),
+}
+
+impl Segment {
+    /// Position along the segment (in [0, 1]) and distance to it
+    fn project(&self, p: Vec2) -> (f32, f32) {
+        let ab = self.b - self.a;
+        let t = ((p - self.a).dot(ab) / ab.length_squared()).clamp(0., 1.);
+        (t, (p - (self.a + ab * t)).length())
+    }
+
+    fn touches(&self, min: Vec2, max: Vec2) -> bool {
+        let (lo, hi) = (self.a.min(self.b), self.a.max(self.b));
+        lo.x - REACH <= max.x
+            && hi.x + REACH >= min.x
+            && lo.y - REACH <= max.y
+            && hi.y + REACH >= min.y
+    }
+}
+
+/// Ground at a block once rivers are accounted for
+#[derive(Clone, Copy, Debug)]
+pub struct RiverCell {
+    pub ground: i32,
+    // water fills everything between the ground and this level
+    pub water: i32,
+    // 1 next to a river, 0 further than WET_RANGE
+    pub wetness: f32,
+}
+
+/// River network flowing down from high cells to the sea. Only depends on the seed
+/// and the terrain heights, so any column can find the rivers that cross it on its own.
+pub struct Rivers {
+    seed: i32,
+    meander: DomainWarp,
+    regions: DashMap<(i32, i32), Arc<Vec<Segment>>>,
+}
+
+impl Rivers {
+    pub fn new(seed: i32) -> Self {
+        Rivers {
+            seed,
+            meander: DomainWarp::new(seed.wrapping_add(20), 2, 1. / 96., MEANDER),
+            regions: DashMap::new(),
+        }
+    }
+
+    fn rng(&self, (x, z): (i32, i32)) -> usize {
+        BlockPos2d { x, z }.prng(self.seed ^ RIVER_SALT)
+    }
+
+    /// Center of a cell, jittered so rivers don't follow the grid
+    fn node(&self, cell: (i32, i32)) -> Vec2 {
+        let rng = self.rng(cell);
+        let jitter = |bits: usize| ((bits & 0xFF) as f32 / 255. - 0.5) * RIVER_CELL as f32 * 0.6;
+        Vec2::new(
+            (cell.0 * RIVER_CELL + RIVER_CELL / 2) as f32 + jitter(rng),
+            (cell.1 * RIVER_CELL + RIVER_CELL / 2) as f32 + jitter(rng >> 8),
+        )
+    }
+
+    fn level(height: i32) -> f32 {
+        (height - 2).max(WATER_H) as f32
+    }
+
+    fn width(cells: i32) -> f32 {
+        SOURCE_WIDTH + WIDTH_GROWTH * cells as f32
+    }
+
+    /// Follows every river that can reach the region, keeps the segments that affect it
+    fn trace_region(&self, region: (i32, i32), height: &impl Fn(i32, i32) -> i32) -> Vec<Segment> {
+        // node heights are looked up many times by the rivers crossing the region
+        let mut heights: HashMap<(i32, i32), i32> = HashMap::new();
+        let mut node_height = |cell: (i32, i32)| {
+            if let Some(&h) = heights.get(&cell) {
+                return h;
+            }
+            let node = self.node(cell);
+            let h = height(node.x as i32, node.y as i32);
+            heights.insert(cell, h);
+            h
+        };
+        let (rx, rz) = (region.0 * REGION_CELLS, region.1 * REGION_CELLS);
+        let min = Vec2::new(rx as f32, rz as f32) * RIVER_CELL as f32;
+        let max = min + Vec2::splat((REGION_CELLS * RIVER_CELL) as f32);
+        let sources = |start: i32| {
+            (start - MAX_RIVER_CELLS - 1)..(start + REGION_CELLS + MAX_RIVER_CELLS + 1)
+        };
+        let mut segments = Vec::new();
+        for source in iproduct!(sources(rx), sources(rz)) {
+            if self.rng(source) >> 16 & 0xFFFF >= SOURCE_CHANCE * 0xFFFF / 100
+                || node_height(source) < SOURCE_MIN_HEIGHT
+            {
+                continue;
+            }
+            let mut cell = source;
+            for length in 0..MAX_RIVER_CELLS {
+                let h = node_height(cell);
+                if h <= WATER_H {
+                    // reached the sea
+                    break;
+                }
+                let mut next = cell;
+                for (dx, dz) in iproduct!(-1..=1, -1..=1) {
+                    let other = (cell.0 + dx, cell.1 + dz);
+                    if node_height(other) < node_height(next) {
+                        next = other;
+                    }
+                }
+                if next == cell {
+                    // nowhere lower to go, the river ends in a pond
+                    break;
+                }
+                let segment = Segment {
+                    a: self.node(cell),
+                    b: self.node(next),
+                    level: (Self::level(h), Self::level(node_height(next))),
+                    width: (Self::width(length), Self::width(length + 1)),
+                };
+                if segment.touches(min, max) {
+                    segments.push(segment);
+                }
+                cell = next;
+            }
+        }
+        segments
+    }
+
+    /// Regions whose segments can affect the ground between `min` and `max` (in blocks)
+    fn regions_around(min: Vec2, max: Vec2) -> impl Iterator<Item = (i32, i32)> {
+        let region_size = (REGION_CELLS * RIVER_CELL) as f32;
+        let first = ((min - REACH) / region_size).floor();
+        let last = ((max + REACH) / region_size).floor();
+        iproduct!(
+            first.x as i32..=last.x as i32,
+            first.y as i32..=last.y as i32
+        )
+    }
+
+    /// Segments that can affect the ground between `min` and `max` (in blocks)
+    pub fn segments(&self, min: Vec2, max: Vec2, height: impl Fn(i32, i32) -> i32) -> Vec<Segment> {
+        let mut res = Vec::new();
+        for region in Self::regions_around(min, max) {
+            let cached = self.regions.get(&region).map(|segments| segments.clone());
+            let segments = cached.unwrap_or_else(|| {
+                // traced without holding the lock, if another thread was faster its segments are kept
+                let traced = Arc::new(self.trace_region(region, &height));
+                self.regions.entry(region).or_insert(traced).clone()
+            });
+            res.extend(segments.iter().filter(|segment| segment.touches(min, max)));
+        }
+        res
+    }
+
+    /// Drops the regions that none of the areas (`min`, `max` in blocks) need
+    pub fn retain(&self, areas: impl IntoIterator<Item = (Vec2, Vec2)>) {
+        let needed: HashSet<(i32, i32)> = areas
+            .into_iter()
+            .flat_map(|(min, max)| Self::regions_around(min, max))
+            .collect();
+        self.regions.retain(|region, _| needed.contains(region));
+    }
+
+    /// Carves the channels and banks of the nearby rivers into the ground at x,z
+    pub fn shape(&self, segments: &[Segment], x: i32, z: i32, ground: i32) -> RiverCell {
+        let mut cell = RiverCell {
+            ground,
+            water: WATER_H,
+            wetness: 0.,
+        };
+        if segments.is_empty() {
+            return cell;
+        }
+        let (wx, wz) = self.meander.warp2(x as f32, z as f32);
+        for segment in segments {
+            let (t, dist) = segment.project(Vec2::new(wx, wz));
+            let half_width = lerp(segment.width.0, segment.width.1, t) / 2.;
+            let level = lerp(segment.level.0, segment.level.1, t);
+            let wetness = 1. - (dist - half_width).max(0.) / WET_RANGE;
+            cell.wetness = cell.wetness.max(wetness.clamp(0., 1.));
+            if dist < half_width {
+                // deeper in the middle and for wider rivers
+                let depth = (2. + half_width / 4.) * (1. - (dist / half_width).powi(2)).sqrt();
+                cell.ground = cell.ground.min((level - 1. - depth) as i32);
+                // the water doesn't rise above the banks where the terrain dips
+                cell.water = cell.water.max((level as i32).min(ground - 1));
+            } else if dist < half_width + BANK {
+                let bank = level + (dist - half_width) / BANK * (ground as f32 - level);
+                cell.ground = cell.ground.min(bank as i32);
+            }
+        }
+        cell
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a slope down to the sea along x, high enough for sources inland
+    fn height(x: i32, _z: i32) -> i32 {
+        WATER_H + 120 - x / 16
+    }
+
+    #[test]
+    fn trimmed_regions_are_traced_again_the_same() {
+        let rivers = Rivers::new(7);
+        let (min, max) = (Vec2::new(-100., 40.), Vec2::new(300., 500.));
+        let segments = format!("{:?}", rivers.segments(min, max, height));
+        assert_ne!(segments, "[]");
+        let far = (Vec2::splat(50_000.), Vec2::splat(50_100.));
+        rivers.retain([(min, max)]);
+        assert!(!rivers.regions.is_empty());
+        rivers.retain([far]);
+        assert!(rivers.regions.is_empty());
+        assert_eq!(format!("{:?}", rivers.segments(min, max, height)), segments);
+    }
+}
@@ -1,111 +1,164 @@
-use crate::gen::earth_gen::Earth;
-use crate::world::pos2d::Pos2d;
+use crate::gen::biomes::Biome;
+use crate::gen::config::{ActiveGenConfig, GenConfig, GEN_CONFIG_PATH};
+use crate::gen::generator::WorldGenerator;
+use crate::gen::pipeline::{GenPipeline, Run, Spill, SpillLayer};
+use crate::gen::presets::WorldPreset;
+use crate::gen::trees::Tree;
 use crate::world::ColPos;
+use crate::world::ColUnloadEvent;
 use crate::world::LoadOrders;
+use crate::world::PlayerArea;
 use crate::world::VoxelWorld;
-use bevy::prelude::*;
-use std::collections::HashMap;
+use bevy::{prelude::*, tasks::AsyncComputeTaskPool};
+use std::sync::Arc;
 
-pub const MAX_GEN_TIME_MS: u32 = 20;
+// columns waiting for their generation, per thread of the async compute pool
+pub const GEN_REQUESTS_PER_THREAD: usize = 2;
+pub const DEFAULT_SEED: i32 = 0x5EED;
 
-#[derive(Resource, Default)]
+/// Seed shared by every generation stage, the same seed always yields the same world.
+/// Can be overridden with the `RIVERBED_SEED` environment variable.
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WorldSeed(pub i32);
+
+impl Default for WorldSeed {
+    fn default() -> Self {
+        let seed = std::env::var("RIVERBED_SEED")
+            .ok()
+            .and_then(|seed| seed.parse().ok())
+            .unwrap_or(DEFAULT_SEED);
+        WorldSeed(seed)
+    }
+}
+
+#[derive(Resource)]
 pub struct TerrainGenerationQueue {
-    pub queue: Vec<(Pos2d<62>, u32)>, // col_pos and priority/distance
-    pub in_progress: Option<Pos2d<62>>,
-    pub generator: Option<Earth>,
-    // Generation state
-    pub gen_state: Option<GenerationState>,
+    pub generator: Option<Arc<dyn WorldGenerator>>,
+    pub pipeline: GenPipeline,
 }
-#[derive(Default, Copy, Clone)]
+#[derive(Default, Clone)]
 pub struct GenerationState {
     pub col_pos: ColPos,
-    pub current_x: usize,
-    pub current_z: usize,
-    pub top_height: Option<i32>, // Store the calculated height when processing blocks
-    pub base_height: i32,
-    pub hill_height: i32,
-    pub phase: GenerationPhase,
+    pub top_height: Option<i32>, // Highest block of the column so far
+    pub heights: Vec<i32>,       // Ground heights, computed in one batch when the column starts
+    pub water: Vec<i32>,         // Water levels, above WATER_H in rivers
+    pub biomes: Vec<Biome>,      // Computed along with the heights
+    pub spills: Vec<Spill>,      // Runs for this column and its neighbours, left by Features
+    pub spilled: Vec<(SpillLayer, Vec<Run>)>, // What was spilled here, sorted for Finalize
+    pub trees: Vec<Tree>,        // Rooted here by Features, kept once finalized to be spilled again
 }
-#[derive(Default, Copy, Clone)]
-pub enum GenerationPhase {
-    #[default]
-    CalculatingHeights,
-    MarkingChunks,
-    Completed,
+
+impl GenerationState {
+    pub fn new(col_pos: ColPos) -> Self {
+        GenerationState {
+            col_pos,
+            ..Default::default()
+        }
+    }
 }
 
-pub fn setup_gen_system(mut commands: Commands) {
-    // Initialize the terrain generator
-    let generator = Earth::new(HashMap::new());
+pub fn setup_gen_system(
+    mut commands: Commands,
+    seed: Res<WorldSeed>,
+    preset: Res<WorldPreset>,
+    asset_server: Res<AssetServer>,
+) {
+    // Initialize the terrain generator with the built-in config, the asset replaces it once loaded
+    info!("Generating {:?} world with seed {}", *preset, seed.0);
+    let config = GenConfig::default();
+    let generator = preset.build(seed.0, &config);
     commands.insert_resource(TerrainGenerationQueue {
-        queue: Vec::new(),
-        in_progress: None,
-        generator: Some(generator),
-        gen_state: None,
+        pipeline: GenPipeline::for_generator(generator.as_ref()),
+        generator: Some(Arc::from(generator)),
+    });
+    commands.insert_resource(ActiveGenConfig {
+        handle: asset_server.load(GEN_CONFIG_PATH),
+        config,
     });
 }
 pub fn queue_terrain_generation(
     mut terrain_queue: ResMut<TerrainGenerationQueue>,
     load_orders: Res<LoadOrders>,
 ) {
-    // Only add to the queue if we're not currently processing a chunk
-    if terrain_queue.in_progress.is_none() {
-        if let Some(mut orders) = load_orders.to_generate.try_write_arc() {
-            if let Some((col_pos, priority)) = orders.pop() {
-                terrain_queue.queue.push((col_pos, priority));
-            }
+    // Keep enough columns requested for every thread to have something to do
+    let max_requests = AsyncComputeTaskPool::get().thread_num() * GEN_REQUESTS_PER_THREAD;
+    let mut pending = terrain_queue.pipeline.pending();
+    if pending >= max_requests {
+        return;
+    }
+    if let Some(mut orders) = load_orders.to_generate.try_write_arc() {
+        while pending < max_requests {
+            let Some((col_pos, priority)) = orders.pop() else {
+                break;
+            };
+            terrain_queue.pipeline.request(col_pos, priority);
+            pending += 1;
         }
     }
 }
 pub fn process_terrain_generation(
     mut terrain_queue: ResMut<TerrainGenerationQueue>,
-    mut world: ResMut<VoxelWorld>,
+    world: Res<VoxelWorld>,
 ) {
-    let start_time = std::time::Instant::now();
-    let col_pos_wrapped = terrain_queue.in_progress;
-    let mut gen_state_wrapped = terrain_queue.gen_state;
-    let gen_unwrapped = &terrain_queue.generator;
-    if gen_unwrapped.is_none() {
+    let terrain_queue = &mut *terrain_queue;
+    terrain_queue.pipeline.poll_tasks(&world);
+    let Some(gen) = terrain_queue.generator.as_ref() else {
         return;
+    };
+    // stages run on the async compute pool, the main thread only moves finished columns in
+    let max_tasks = AsyncComputeTaskPool::get().thread_num();
+    terrain_queue.pipeline.spawn_tasks(gen, max_tasks);
+}
+pub fn forget_unloaded_cols(
+    mut terrain_queue: ResMut<TerrainGenerationQueue>,
+    mut ev_unload: EventReader<ColUnloadEvent>,
+) {
+    let mut forgot = false;
+    for ColUnloadEvent(col_pos) in ev_unload.read() {
+        terrain_queue.pipeline.forget(*col_pos);
+        forgot = true;
     }
-    if col_pos_wrapped.is_some() {
-        let col_pos = col_pos_wrapped.unwrap();
-        let gen = gen_unwrapped.as_ref().unwrap();
-        // If we don't have a generation state, initialize one
-        if gen_state_wrapped.is_none() {
-            gen_state_wrapped = Some(GenerationState {
-                col_pos,
-                current_x: 0,
-                current_z: 0,
-                top_height: None,
-                base_height: 40, // Constants from the original function
-                hill_height: 20,
-                phase: GenerationPhase::CalculatingHeights,
-            });
-        }
-
-        let mut gen_state = gen_state_wrapped.unwrap();
-
-        let completed =
-            gen.process_generation_chunk(&mut gen_state, &world, MAX_GEN_TIME_MS, start_time);
-
-        // If generation is complete, clean up
-        terrain_queue.gen_state = Some(gen_state);
-        if completed {
-            terrain_queue.gen_state = None;
-            terrain_queue.in_progress = None;
-        }
+    if let Some(generator) = terrain_queue.generator.as_ref().filter(|_| forgot) {
+        // the columns left in the pipeline are the loaded and the pending ones
+        let needed: Vec<ColPos> = terrain_queue.pipeline.cols().collect();
+        generator.trim_caches(&needed);
     }
-    // Get next chunk to process if we're not currently processing one
-    if terrain_queue.in_progress.is_none() && !terrain_queue.queue.is_empty() {
-        // Sort by priority if needed
-        terrain_queue
-            .queue
-            .sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-
-        // Take the highest priority chunk
-        if let Some((pos, _)) = terrain_queue.queue.pop() {
-            terrain_queue.in_progress = Some(pos);
-        }
+}
+/// Rebuilds the generator when its config asset is loaded or edited,
+/// and generates the loaded area again with it
+#[allow(clippy::too_many_arguments)]
+pub fn reload_gen_config(
+    mut ev_asset: EventReader<AssetEvent<GenConfig>>,
+    configs: Res<Assets<GenConfig>>,
+    mut active: ResMut<ActiveGenConfig>,
+    mut terrain_queue: ResMut<TerrainGenerationQueue>,
+    mut load_orders: ResMut<LoadOrders>,
+    load_area: Option<Res<PlayerArea>>,
+    seed: Res<WorldSeed>,
+    preset: Res<WorldPreset>,
+) {
+    let handle_id = active.handle.id();
+    let changed = ev_asset.read().any(|ev| match ev {
+        AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id } => *id == handle_id,
+        _ => false,
+    });
+    if !changed {
+        return;
+    }
+    let Some(config) = configs.get(handle_id) else {
+        return;
+    };
+    if *config == active.config {
+        return;
+    }
+    info!("Generator config changed, generating the world again");
+    active.config = config.clone();
+    let generator = preset.build(seed.0, &active.config);
+    // dropping the pipeline cancels its tasks, the loaded columns go through the unload orders
+    // so their entities go with them
+    terrain_queue.pipeline = GenPipeline::for_generator(generator.as_ref());
+    terrain_queue.generator = Some(Arc::from(generator));
+    if let Some(load_area) = load_area {
+        load_orders.regenerate(&load_area);
     }
 }
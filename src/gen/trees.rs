@@ -0,0 +1,247 @@
+use super::{
+    biomes::Biome,
+    earth_gen::Earth,
+    generator::WorldGenerator,
+    pipeline::{Run, Spill, SpillLayer},
+};
+use crate::{
+    block::Block,
+    world::{BlockPos, BlockPos2d, ColPos, ColedPos, CHUNK_S1I, WATER_H},
+};
+use itertools::iproduct;
+
+// trees are spread on a grid, with at most one tree per cell
+pub const TREE_CELL: i32 = 24;
+// furthest a tree can reach horizontally from its trunk, in blocks
+const TREE_REACH: i32 = 17;
+// how many columns away from the one it's rooted in a tree can reach
+pub const TREE_SPILL_RADIUS: i32 = (TREE_REACH + CHUNK_S1I - 1) / CHUNK_S1I;
+// trunks go a bit into the ground so that they don't float on slopes
+const TRUNK_SINK: i32 = 2;
+const TREE_SALT: i32 = 0x7EE5;
+
+#[derive(Clone)]
+enum Canopy {
+    Ellipsoid {
+        center_y: i32,
+        radius: f32,
+        half_height: f32,
+    },
+    Cone {
+        bottom: i32,
+        height: f32,
+        radius: f32,
+    },
+}
+
+/// A tree, sized in blocks (1/8 of a world unit)
+#[derive(Clone)]
+pub struct Tree {
+    log: Block,
+    base: BlockPos,
+    trunk_height: i32,
+    trunk_radius: f32,
+    canopy: Canopy,
+}
+
+impl Tree {
+    pub fn new(log: Block, base: BlockPos, rng: usize) -> Self {
+        let vary = |max: usize| (rng % (max + 1)) as i32;
+        match log {
+            Block::SpruceLog => {
+                let trunk_height = 44 + vary(14);
+                Tree {
+                    log,
+                    base,
+                    trunk_height,
+                    trunk_radius: 1.5,
+                    canopy: Canopy::Cone {
+                        bottom: base.y + 10,
+                        height: (trunk_height - 6) as f32,
+                        radius: 11. + vary(3) as f32,
+                    },
+                }
+            }
+            Block::BirchLog => {
+                let trunk_height = 34 + vary(10);
+                Tree {
+                    log,
+                    base,
+                    trunk_height,
+                    trunk_radius: 1.2,
+                    canopy: Canopy::Ellipsoid {
+                        center_y: base.y + trunk_height - 4,
+                        radius: 8. + vary(2) as f32,
+                        half_height: 12.,
+                    },
+                }
+            }
+            _ => {
+                let trunk_height = 28 + vary(10);
+                Tree {
+                    log,
+                    base,
+                    trunk_height,
+                    trunk_radius: 1.8,
+                    canopy: Canopy::Ellipsoid {
+                        center_y: base.y + trunk_height,
+                        radius: 13. + vary(3) as f32,
+                        half_height: 10.,
+                    },
+                }
+            }
+        }
+    }
+
+    fn leaves(&self) -> Block {
+        match self.log {
+            Block::SpruceLog => Block::SpruceLeaves,
+            Block::BirchLog => Block::BirchLeaves,
+            _ => Block::OakLeaves,
+        }
+    }
+
+    /// Vertical extent of the canopy at a horizontal distance from the trunk
+    fn canopy_span(&self, dist: f32) -> Option<(i32, i32)> {
+        match self.canopy {
+            Canopy::Ellipsoid {
+                center_y,
+                radius,
+                half_height,
+            } => {
+                let d = dist / radius;
+                if d > 1. {
+                    return None;
+                }
+                let half = (half_height * (1. - d * d).sqrt()) as i32;
+                Some((center_y - half, center_y + half))
+            }
+            Canopy::Cone {
+                bottom,
+                height,
+                radius,
+            } => {
+                if dist > radius {
+                    return None;
+                }
+                Some((bottom, bottom + (height * (1. - dist / radius)) as i32))
+            }
+        }
+    }
+
+    /// Adds the parts of the tree that fall in the column
+    pub fn runs(&self, col_pos: ColPos, leaves: &mut Vec<Run>, trunks: &mut Vec<Run>) {
+        let (x0, z0) = (col_pos.x * CHUNK_S1I, col_pos.z * CHUNK_S1I);
+        for dx in -TREE_REACH..=TREE_REACH {
+            let x = self.base.x + dx - x0;
+            if !(0..CHUNK_S1I).contains(&x) {
+                continue;
+            }
+            for dz in -TREE_REACH..=TREE_REACH {
+                let z = self.base.z + dz - z0;
+                if !(0..CHUNK_S1I).contains(&z) {
+                    continue;
+                }
+                let pos2d = (x as usize, z as usize);
+                let dist = ((dx * dx + dz * dz) as f32).sqrt();
+                if dist <= self.trunk_radius {
+                    trunks.push((
+                        pos2d,
+                        self.base.y - TRUNK_SINK,
+                        self.base.y + self.trunk_height,
+                        self.log,
+                    ));
+                }
+                if let Some((bottom, top)) = self.canopy_span(dist) {
+                    leaves.push((pos2d, bottom, top, self.leaves()));
+                }
+            }
+        }
+    }
+}
+
+fn pick_species(trees: &[(Block, f32)], roll: f32) -> Option<Block> {
+    let total: f32 = trees.iter().map(|(_, weight)| weight).sum();
+    let mut roll = roll * total;
+    for (log, weight) in trees {
+        if roll < *weight {
+            return Some(*log);
+        }
+        roll -= weight;
+    }
+    trees.last().map(|(log, _)| *log)
+}
+
+/// The tree of a grid cell if it's rooted in the column, given the ground, water level
+/// and biome at a block of the column once it's carved. Only depends on the seed and the cell,
+/// not on what has been generated so far.
+fn tree_in_cell(
+    earth: &Earth,
+    col_pos: ColPos,
+    cell: BlockPos2d,
+    ground: &impl Fn(ColedPos) -> (i32, i32, Biome),
+) -> Option<Tree> {
+    let rng = cell.prng(earth.seed() ^ TREE_SALT);
+    let x = cell.x * TREE_CELL + (rng % TREE_CELL as usize) as i32;
+    let z = cell.z * TREE_CELL + ((rng >> 8) % TREE_CELL as usize) as i32;
+    let (col, pos2d) = <(ColPos, ColedPos)>::from(BlockPos2d { x, z });
+    if col != col_pos {
+        return None;
+    }
+    let (ground, water, biome) = ground(pos2d);
+    // no trees on beaches or in rivers
+    if ground <= WATER_H || water > ground {
+        return None;
+    }
+    let chance = ((rng >> 16) & 0xFFFF) as f32 / 65536.;
+    if chance >= biome.tree_density() {
+        return None;
+    }
+    let roll = ((rng >> 32) & 0xFFFF) as f32 / 65536.;
+    let log = pick_species(biome.trees(), roll)?;
+    Some(Tree::new(log, BlockPos { x, y: ground, z }, rng >> 48))
+}
+
+/// Trees rooted in the column, on the ground given by `ground` (see `tree_in_cell`) after carving
+pub fn tree_roots(
+    earth: &Earth,
+    col_pos: ColPos,
+    ground: impl Fn(ColedPos) -> (i32, i32, Biome),
+) -> Vec<Tree> {
+    let (x0, z0) = (col_pos.x * CHUNK_S1I, col_pos.z * CHUNK_S1I);
+    let cells =
+        |start: i32| start.div_euclid(TREE_CELL)..=(start + CHUNK_S1I - 1).div_euclid(TREE_CELL);
+    iproduct!(cells(x0), cells(z0))
+        .filter_map(|(x, z)| tree_in_cell(earth, col_pos, BlockPos2d { x, z }, &ground))
+        .collect()
+}
+
+/// The parts of the column's trees that go in it and its neighbours.
+/// Leaves are spilled on the canopy layer so they don't replace the ground.
+pub fn tree_spills(col_pos: ColPos, trees: &[Tree]) -> Vec<Spill> {
+    let mut spills = Vec::new();
+    for (dx, dz) in iproduct!(
+        -TREE_SPILL_RADIUS..=TREE_SPILL_RADIUS,
+        -TREE_SPILL_RADIUS..=TREE_SPILL_RADIUS
+    ) {
+        let target = ColPos {
+            x: col_pos.x + dx,
+            z: col_pos.z + dz,
+        };
+        let (mut leaves, mut trunks) = (Vec::new(), Vec::new());
+        for tree in trees.iter() {
+            tree.runs(target, &mut leaves, &mut trunks);
+        }
+        spills.push(Spill {
+            target,
+            layer: SpillLayer::Canopy,
+            runs: leaves,
+        });
+        spills.push(Spill {
+            target,
+            layer: SpillLayer::Solid,
+            runs: trunks,
+        });
+    }
+    spills
+}
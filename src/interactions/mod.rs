@@ -1,7 +1,9 @@
 use bevy::prelude::*;
 pub mod place;
+pub mod target;
 
 use place::PlacePlugin;
+use target::TargetPlugin;
 
 pub struct PlayerInteractionsPlugin;
 
@@ -9,6 +11,7 @@ impl Plugin for PlayerInteractionsPlugin {
     fn build(&self, app: &mut App) {
         app
             .add_plugins(PlacePlugin)
+            .add_plugins(TargetPlugin)
             //b
         ;
     }
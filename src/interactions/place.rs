@@ -1,7 +1,7 @@
+use super::target::BuildingState;
 use crate::{
     block::Block,
     controls::action_mapping::{ActionState, GameAction},
-    render::draw_chunks::BuildingState,
     world::{pos3d::Pos3d, VoxelWorld},
 };
 use bevy::prelude::*;
@@ -12,7 +12,7 @@ pub struct PlaceBlockEvent {
     pub block: Block,
     pub destination: PlaceDestination,
 }
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PlaceDestination {
     #[default]
     World,
@@ -24,19 +24,25 @@ pub fn read_general_event(
     building_state: Res<BuildingState>,
     mut place_events: EventWriter<PlaceBlockEvent>,
 ) {
+    // the builder scene sends its own events
+    if building_state.destination != PlaceDestination::World {
+        return;
+    }
+    let Some(target) = building_state.target else {
+        return;
+    };
     if action_state.just_released(GameAction::PrimaryAction) {
-        if let Some(pos) = building_state.current_position {
-            let p: Pos3d<1> = Pos3d {
-                x: (pos.x * 8.) as i32,
-                y: (pos.y * 8.) as i32,
-                z: (pos.z * 8.) as i32,
-            };
-            place_events.write(PlaceBlockEvent {
-                pos: p,
-                block: Block::Stone,
-                destination: PlaceDestination::World,
-            });
-        }
+        place_events.write(PlaceBlockEvent {
+            pos: target.place_pos,
+            block: Block::Stone,
+            destination: PlaceDestination::World,
+        });
+    } else if action_state.just_released(GameAction::MiddleAction) {
+        place_events.write(PlaceBlockEvent {
+            pos: target.pos,
+            block: Block::Air,
+            destination: PlaceDestination::World,
+        });
     }
 }
 fn place_block(
@@ -46,10 +52,14 @@ fn place_block(
 ) {
     let was_empty = place_events.is_empty();
     for evt in place_events.read() {
+        // breaking air is a no-op, the builder can target its (empty) walls
+        if evt.block == Block::Air && world.get_block_safe(evt.pos) == Block::Air {
+            continue;
+        }
         world.set_block(evt.pos, evt.block, true);
     }
     if was_empty == false {
-        building_state.current_position = None;
+        building_state.target = None;
     }
 }
 
@@ -0,0 +1,119 @@
+use super::place::PlaceDestination;
+use crate::{
+    agents::{PlayerControlled, TargetBlock},
+    render::camera::MainCamera,
+    world::{BlockRayCastHit, VoxelWorld},
+};
+use bevy::{ecs::system::SystemParam, prelude::*, window::PrimaryWindow};
+use bevy_egui::EguiContexts;
+
+// how far (in world units) from the camera blocks can be targeted
+pub const TARGET_REACH: f32 = 64.;
+const VOXEL_HALF_SIZE: f32 = 0.0625;
+
+#[derive(Resource, Default)]
+pub struct BuildingState {
+    pub target: Option<BlockRayCastHit>,
+    pub destination: PlaceDestination,
+}
+
+#[derive(Component)]
+pub struct BuildingPreview;
+
+pub fn setup_building_preview(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    // Spawn an initially invisible preview cube
+    commands.spawn((
+        BuildingPreview,
+        Mesh3d(meshes.add(Cuboid::new(0.125, 0.125, 0.125))),
+        MeshMaterial3d(materials.add(Color::srgba(1., 1., 1., 0.6))),
+        Transform::from_xyz(0.0, 0.0, 0.0),
+        Pickable::IGNORE,
+        Visibility::Hidden,
+    ));
+}
+
+/// Used by both the world and the builder scene so that targeting behaves the same in each
+/// The block target and its placement preview, which are always set together
+#[derive(SystemParam)]
+pub struct Targeting<'w, 's> {
+    pub state: ResMut<'w, BuildingState>,
+    preview:
+        Query<'w, 's, (&'static mut Transform, &'static mut Visibility), With<BuildingPreview>>,
+}
+
+impl Targeting<'_, '_> {
+    pub fn set(&mut self, hit: Option<BlockRayCastHit>, destination: PlaceDestination) {
+        self.state.destination = destination;
+        self.state.target = hit;
+        let Ok((mut transform, mut visibility)) = self.preview.single_mut() else {
+            return;
+        };
+        match hit {
+            Some(hit) => {
+                transform.translation = Vec3::from(hit.place_pos) + VOXEL_HALF_SIZE;
+                *visibility = Visibility::Visible;
+            }
+            None => *visibility = Visibility::Hidden,
+        }
+    }
+}
+
+pub fn update_world_target(
+    mut contexts: EguiContexts,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    cam_query: Query<(&Camera, &GlobalTransform), With<MainCamera>>,
+    mut target_query: Query<&mut TargetBlock, With<PlayerControlled>>,
+    mut targeting: Targeting,
+    world: Res<VoxelWorld>,
+) {
+    // egui windows (like the builder view) take care of their own targeting
+    if contexts.ctx_mut().is_pointer_over_area() {
+        // but a world target left from before would still be placed against
+        if targeting.state.destination == PlaceDestination::World {
+            if let Ok(mut target) = target_query.single_mut() {
+                if target.0.is_some() {
+                    target.0 = None;
+                }
+            }
+            targeting.set(None, PlaceDestination::World);
+        }
+        return;
+    }
+    let Ok((camera, cam_transform)) = cam_query.single() else {
+        return;
+    };
+    // aim with the cursor if there's one, at the center of the screen otherwise
+    let ray = windows
+        .single()
+        .ok()
+        .and_then(|window| window.cursor_position())
+        .and_then(|cursor| camera.viewport_to_world(cam_transform, cursor).ok())
+        .unwrap_or(Ray3d::new(
+            cam_transform.translation(),
+            cam_transform.forward(),
+        ));
+    let hit = world.raycast(ray.origin, *ray.direction, TARGET_REACH);
+    if let Ok(mut target) = target_query.single_mut() {
+        // avoid triggering change detection when the target stays the same
+        if target.0 != hit {
+            target.0 = hit;
+        }
+    }
+    targeting.set(hit, PlaceDestination::World);
+}
+
+pub struct TargetPlugin;
+impl Plugin for TargetPlugin {
+    fn build(&self, app: &mut bevy::prelude::App) {
+        app
+            .init_resource::<BuildingState>()
+            .add_systems(Startup, setup_building_preview)
+            .add_systems(Update, update_world_target)
+        //b
+        ;
+    }
+}
@@ -1,10 +1,10 @@
-use super::binary_greedy_meshing::MeshData;
+use super::mesh_chunks::create_ao_texture_data;
 use super::shared_load_area::{setup_shared_load_area, update_shared_load_area, SharedLoadArea};
 use super::texture_array::TextureArrayPlugin;
 use super::texture_array::{ArrayTextureMaterial, BlockTextureArray};
 use crate::scenes::builder::systems::BUILDER_CHUNK_POS;
 use crate::world::pos2d::chunks_in_col;
-use crate::world::{range_around, ColUnloadEvent, LoadAreaAssigned, PlayerArea};
+use crate::world::{range_around, ColPos, ColUnloadEvent, LoadAreaAssigned, PlayerArea};
 use crate::world::{ChunkPos, VoxelWorld, CHUNK_S1, Y_CHUNKS};
 use avian3d::math::Quaternion;
 use avian3d::prelude::{Collider, RigidBody};
@@ -12,13 +12,11 @@ use bevy::color::palettes::css;
 use bevy::pbr::ExtendedMaterial;
 use bevy::prelude::*;
 use bevy::render::primitives::Aabb;
+use bevy::tasks::{block_on, AsyncComputeTaskPool, Task};
 use itertools::iproduct;
-use std::collections::{BTreeSet, HashMap};
-use std::time::Instant;
+use std::collections::HashMap;
 const GRID_GIZMO_LEN: i32 = 4;
 
-pub const MAX_MESHING_MS: u32 = 5;
-
 #[derive(Debug, Component)]
 pub struct LOD(pub usize);
 
@@ -72,90 +70,20 @@ fn chunk_aabb_gizmos(mut gizmos: Gizmos, load_area: Res<PlayerArea>) {
     }
 }
 
+/// Meshing of a snapshot of a chunk, running on the async compute pool
+struct MeshTask {
+    // revision of the chunk when the snapshot was taken
+    revision: u64,
+    dist: u32,
+    // the collider is None if the mesh can't make one
+    task: Task<Option<(Mesh, Option<Collider>, Image)>>,
+}
+
 #[derive(Resource, Default)]
 pub struct MeshGenerationQueue {
     queue: Vec<(ChunkPos, u32)>,
-    in_progress: Option<(ChunkPos, u32)>,
-    // Track meshing state between frames
-    meshing_state: Option<ChunkMeshingState>,
-}
-
-pub struct ChunkMeshingState {
-    // Current stage of meshing
-    pub stage: MeshingStage,
-    pub mesh_data: MeshData,
-    pub voxels: Vec<u16>,
-    pub transparents: BTreeSet<u16>,
-    pub next_vertex_index: i32,
-    pub vertex_map: HashMap<
-        (
-            (i32, i32, i32), // position
-            i32,
-            i32,
-            i32, // normal
-            u8,
-            u8,
-            u8,
-            u8, // color
-            i32,
-            i32, // uv
-        ),
-        i32,
-    >,
-    pub all_positions: Vec<[f32; 3]>,
-    pub all_normals: Vec<[f32; 3]>,
-    pub all_indices: Vec<u16>,
-    pub all_uvs: Vec<[f32; 2]>,
-    pub all_colors: Vec<[f32; 4]>,
-    // pub all_quad_sizes: Vec<[f32; 2]>,
-    pub all_physics_quads: Vec<[Vec3; 4]>,
-    pub is_empty: bool,
-    pub current_face: usize,
-    pub current_quad_index: usize,
-    pub quad_batch_size: usize,
-}
-impl Default for ChunkMeshingState {
-    fn default() -> Self {
-        Self {
-            stage: MeshingStage::PrepareData,
-            mesh_data: MeshData::new(),
-            voxels: Vec::new(),
-            transparents: BTreeSet::new(),
-            next_vertex_index: 0,
-            vertex_map: HashMap::new(),
-            all_positions: Vec::new(),
-            all_normals: Vec::new(),
-            all_indices: Vec::new(),
-            all_uvs: Vec::new(),
-            all_colors: Vec::new(),
-            // all_quad_sizes: Vec::new(),
-            all_physics_quads: Vec::new(),
-            is_empty: false,
-            current_face: 0,
-            current_quad_index: 0,
-            quad_batch_size: 50,
-        }
-    }
-}
-impl ChunkMeshingState {
-    pub fn is_overtime(&self, timer: &Instant) -> bool {
-        // Convert MAX_MESHING_MS from milliseconds to nanoseconds
-        const MAX_MESHING_NS: u128 = (MAX_MESHING_MS as u128) * 1_000_000;
-
-        let elapsed_ns = timer.elapsed().as_nanos();
-
-        elapsed_ns > MAX_MESHING_NS
-    }
-}
-#[derive(Default, PartialEq, Eq, Debug)]
-pub enum MeshingStage {
-    #[default]
-    PrepareData,
-    Transparents,
-    GreedyMeshing,
-    ProcessQuads,
-    Finalize,
-    Complete,
+    // at most one per chunk, the chunk is kept out of the dirty queue until it's done
+    tasks: HashMap<ChunkPos, MeshTask>,
 }
 
 pub fn queue_mesh_generation(
@@ -163,11 +91,17 @@ pub fn queue_mesh_generation(
     shared_load_area: Res<SharedLoadArea>,
     blocks: Res<VoxelWorld>,
 ) {
-    if mesh_queue.in_progress.is_none() {
-        if let Some(shared_area) = shared_load_area.0.try_read() {
-            if let Some((chunk_pos, dist)) = shared_area.pop_closest_change(&blocks.chunks) {
-                mesh_queue.queue.push((chunk_pos, dist));
-            }
+    // Queue enough chunks for every thread to have one
+    let max_tasks = AsyncComputeTaskPool::get().thread_num();
+    if let Some(shared_area) = shared_load_area.0.try_read() {
+        while mesh_queue.queue.len() + mesh_queue.tasks.len() < max_tasks {
+            let Some(chunk_pos) = blocks.pop_closest_change() else {
+                break;
+            };
+            let dist = <ColPos>::from(chunk_pos)
+                .dist(shared_area.center)
+                .unsigned_abs();
+            mesh_queue.queue.push((chunk_pos, dist));
         }
     }
 }
@@ -175,6 +109,17 @@ pub fn queue_mesh_generation(
 fn still_in_load_area(chunk_pos: ChunkPos, load_area: &PlayerArea) -> bool {
     load_area.col_dists.contains_key(&chunk_pos.into()) || chunk_pos == BUILDER_CHUNK_POS
 }
+
+fn despawn_chunk_mesh(
+    commands: &mut Commands,
+    chunk_ents: &mut ChunkEntities,
+    chunk_pos: ChunkPos,
+) {
+    if let Some(ent) = chunk_ents.0.remove(&chunk_pos) {
+        commands.entity(ent).despawn();
+    }
+}
+
 #[allow(clippy::collapsible_else_if)]
 #[allow(clippy::type_complexity)]
 #[allow(clippy::too_many_arguments)]
@@ -189,164 +134,168 @@ pub fn process_mesh_queue(
         &mut Mesh3d,
         &mut MeshMaterial3d<ExtendedMaterial<StandardMaterial, ArrayTextureMaterial>>,
         &mut LOD,
-        &mut Collider,
         &mut Transform,
     )>,
     mut meshes: ResMut<Assets<Mesh>>,
     block_tex_array: Res<BlockTextureArray>,
     load_area: Res<PlayerArea>,
 ) {
-    // Process current mesh if there is one
-    if let Some((chunk_pos, dist)) = mesh_queue.in_progress {
+    // Mesh a snapshot of every queued chunk in a task of its own
+    let pool = AsyncComputeTaskPool::get();
+    for (chunk_pos, dist) in std::mem::take(&mut mesh_queue.queue) {
+        let Some(mut chunk) = blocks.chunks.get_mut(&chunk_pos) else {
+            blocks.done_meshing(chunk_pos);
+            continue;
+        };
         // Skip if the chunk is no longer in the load area
         if !still_in_load_area(chunk_pos, &load_area) {
-            mesh_queue.in_progress = None;
-            return;
+            drop(chunk);
+            blocks.done_meshing(chunk_pos);
+            continue;
+        }
+        // the occupancy masks tell us for cheap when there's nothing to see
+        if chunk.occupancy.is_empty() || chunk.occupancy.is_buried() {
+            //remove empty mesh chunk
+            despawn_chunk_mesh(&mut commands, &mut chunk_ents, chunk_pos);
+            chunk.changed = false;
+            drop(chunk);
+            blocks.done_meshing(chunk_pos);
+            continue;
+        }
+        let snapshot = chunk.snapshot();
+        let revision = snapshot.revision;
+        let solid = chunk.occupancy.solid.clone();
+        // the chunk can be edited while its snapshot is meshed
+        drop(chunk);
+        let tints: Vec<[f32; 3]> = blocks
+            .biomes
+            .get(&ColPos::from(chunk_pos))
+            .map(|biomes| biomes.iter().map(|biome| biome.tint()).collect())
+            .unwrap_or_default();
+        let task = pool.spawn(async move {
+            let mesh = snapshot.create_face_meshes(&tints)?;
+            // Create compound collider from all cuboids
+            let collider = Collider::trimesh_from_mesh(&mesh);
+            Some((mesh, collider, create_ao_texture_data(&solid)))
+        });
+        mesh_queue.tasks.insert(
+            chunk_pos,
+            MeshTask {
+                revision,
+                dist,
+                task,
+            },
+        );
+    }
+
+    // Upload the meshes that are done
+    let finished: Vec<ChunkPos> = mesh_queue
+        .tasks
+        .iter()
+        .filter(|(_, mesh_task)| mesh_task.task.is_finished())
+        .map(|(chunk_pos, _)| *chunk_pos)
+        .collect();
+    for chunk_pos in finished {
+        let Some(MeshTask {
+            revision,
+            dist,
+            task,
+        }) = mesh_queue.tasks.remove(&chunk_pos)
+        else {
+            continue;
+        };
+        let face_mesh = block_on(task);
+        // queues the chunk again if it was edited meanwhile
+        blocks.done_meshing(chunk_pos);
+        let Some(mut chunk) = blocks.chunks.get_mut(&chunk_pos) else {
+            continue;
+        };
+        if !still_in_load_area(chunk_pos, &load_area) {
+            continue;
+        }
+        // the chunk was edited since the snapshot, not every edit queues it so it's queued here
+        if chunk.revision != revision {
+            drop(chunk);
+            blocks.mark_change_single(chunk_pos);
+            continue;
         }
+        chunk.changed = false;
+        let Some((mesh, new_collider, ao_image)) = face_mesh else {
+            drop(chunk);
+            despawn_chunk_mesh(&mut commands, &mut chunk_ents, chunk_pos);
+            continue;
+        };
+        let existing = chunk_ents.0.get(&chunk_pos).copied();
+        // an updated mesh needs a new AO texture, a new entity can reuse the chunk's
+        if existing.is_some() || chunk.ao_image.is_none() {
+            chunk.ao_image = Some(images.add(ao_image));
+        }
+        let ao_data = chunk.ao_image.clone().unwrap();
+        // done with the chunk, it can be edited again while the mesh is uploaded
+        drop(chunk);
 
         let lod = choose_lod_level(dist);
-        if let Some(mut chunk) = blocks.chunks.get_mut(&chunk_pos) {
-            let face_mesh = chunk.create_face_meshes(mesh_queue.meshing_state.as_mut().unwrap());
-            let meshing_state = mesh_queue.meshing_state.as_ref().unwrap();
-            if meshing_state.is_empty {
-                //remove empty mesh chunk
-                if let Some(ent) = chunk_ents.0.remove(&chunk_pos) {
-                    commands.entity(ent).despawn();
-                }
-                mesh_queue.in_progress = None;
+        let ref_mat = materials.get_mut(&block_tex_array.0).unwrap();
+        let base = ref_mat.base.clone();
+        let new_material = materials.add(ExtendedMaterial {
+            base: StandardMaterial { ..base },
+            extension: ArrayTextureMaterial { ao_data },
+        });
+        // Check if entity already exists for this chunk face
+        if let Some(ent) = existing {
+            if let Ok((mut handle, mut mat, mut old_lod, _)) = mesh_query.get_mut(ent) {
+                mat.0 = new_material;
+                handle.0 = meshes.add(mesh);
+                *old_lod = LOD(lod);
+                match new_collider {
+                    Some(collider) => commands.entity(ent).insert(collider),
+                    None => commands.entity(ent).remove::<Collider>(),
+                };
             } else {
-                if meshing_state.stage == MeshingStage::Complete {
-                    chunk.changed = false;
-                    mesh_queue.in_progress = None;
-
-                    if let Some((mesh, physics_quads)) = face_mesh {
-                        let chunk_aabb =
-                            Aabb::from_min_max(Vec3::ZERO, Vec3::splat((CHUNK_S1 as f32) / 8.));
-                        // Create compound collider from all cuboids
-                        let new_collider = Collider::trimesh_from_mesh(&mesh).unwrap();
-                        // Check if entity already exists for this chunk face
-                        if let Some(ent) = chunk_ents.0.get(&chunk_pos) {
-                            if let Ok((mut handle, mut mat, mut old_lod, mut collider, _)) =
-                                mesh_query.get_mut(*ent)
-                            {
-                                let image = chunk.create_ao_texture_data();
-                                let ao_image_handle = images.add(image);
-                                chunk.ao_image = Some(ao_image_handle.clone());
-                                chunk.meshing = false;
-
-                                let ref_mat = materials.get_mut(&block_tex_array.0).unwrap();
-                                let base = ref_mat.base.clone();
-                                let new_material = materials.add(ExtendedMaterial {
-                                    base: StandardMaterial { ..base },
-                                    extension: ArrayTextureMaterial {
-                                        ao_data: chunk.ao_image.clone().unwrap(),
-                                    },
-                                });
-
-                                mat.0 = new_material;
-                                handle.0 = meshes.add(mesh);
-                                *old_lod = LOD(lod);
-                                *collider = new_collider;
-                            } else {
-                                println!("couldn't get_mut mesh for chunk {}", chunk_pos);
-                            }
-                        } else {
-                            // Create new entity if it doesn't exist
-                            if chunk.ao_image.is_none() {
-                                let image = chunk.create_ao_texture_data();
-                                let ao_image_handle = images.add(image);
-                                chunk.ao_image = Some(ao_image_handle.clone());
-                                chunk.meshing = false;
-                            }
-
-                            let ref_mat = materials.get_mut(&block_tex_array.0).unwrap();
-                            let base = ref_mat.base.clone();
-                            let new_material = materials.add(ExtendedMaterial {
-                                base: StandardMaterial { ..base },
-                                extension: ArrayTextureMaterial {
-                                    ao_data: chunk.ao_image.clone().unwrap(),
-                                },
-                            });
-                            let mesh_handle = meshes.add(mesh);
-                            let mesh_pos = Vec3::new(
-                                (chunk_pos.x as f32) / 8.,
-                                (chunk_pos.y as f32) / 8.,
-                                (chunk_pos.z as f32) / 8.,
-                            ) * CHUNK_S1 as f32;
-                            let ent = commands
-                                .spawn((
-                                    Mesh3d(mesh_handle.clone()),
-                                    MeshMaterial3d(new_material),
-                                    Transform::from_translation(mesh_pos),
-                                    // NoFrustumCulling,
-                                    chunk_aabb,
-                                    LOD(lod),
-                                    Pickable {
-                                        should_block_lower: true,
-                                        is_hoverable: true,
-                                    },
-                                    WorldMesh,
-                                    //SimplifiedMesh(mesh_handle),
-                                    //Physics
-                                    RigidBody::Static, // Static for terrain
-                                    new_collider,
-                                ))
-                                .observe(
-                                    |trigger: Trigger<Pointer<Move>>,
-                                     mut building_state: ResMut<BuildingState>,
-                                     mut preview_query: Query<
-                                        (&mut Transform, &mut Visibility),
-                                        With<BuildingPreview>,
-                                    >| {
-                                        let mv = trigger.event();
-                                        // Convert world position to voxel grid (1/8 unit per voxel)
-                                        if let Some(world_position) = mv.hit.position {
-                                            if let Some(world_normal) = mv.hit.normal {
-                                                let voxel_size = 0.125;
-                                                let voxel_half_size = 0.0625;
-                                                let voxel_pos = world_position / voxel_size;
-                                                let target_voxel_pos =
-                                                    (voxel_pos + world_normal * voxel_size).floor()
-                                                        * voxel_size
-                                                        + voxel_half_size;
-
-                                                if let Ok((mut transform, mut visibility)) =
-                                                    preview_query.single_mut()
-                                                {
-                                                    transform.translation = target_voxel_pos;
-                                                    building_state.current_position =
-                                                        Some(target_voxel_pos - voxel_half_size);
-                                                    *visibility = Visibility::Visible;
-                                                }
-                                            }
-                                        }
-                                    },
-                                )
-                                .id();
-                            chunk_ents.0.insert(chunk_pos, ent);
-                        }
-                    }
-                }
+                println!("couldn't get_mut mesh for chunk {}", chunk_pos);
             }
+        } else {
+            // Create new entity if it doesn't exist
+            let chunk_aabb = Aabb::from_min_max(Vec3::ZERO, Vec3::splat((CHUNK_S1 as f32) / 8.));
+            let mesh_handle = meshes.add(mesh);
+            let mesh_pos = Vec3::new(
+                (chunk_pos.x as f32) / 8.,
+                (chunk_pos.y as f32) / 8.,
+                (chunk_pos.z as f32) / 8.,
+            ) * CHUNK_S1 as f32;
+            let mut ent = commands.spawn((
+                Mesh3d(mesh_handle.clone()),
+                MeshMaterial3d(new_material),
+                Transform::from_translation(mesh_pos),
+                // NoFrustumCulling,
+                chunk_aabb,
+                LOD(lod),
+                WorldMesh,
+                //SimplifiedMesh(mesh_handle),
+                //Physics
+                RigidBody::Static, // Static for terrain
+            ));
+            // a mesh the physics can't use is still drawn, it just can't be collided with
+            if let Some(collider) = new_collider {
+                ent.insert(collider);
+            }
+            chunk_ents.0.insert(chunk_pos, ent.id());
         }
     }
-
-    // Get next mesh to process
-    if mesh_queue.in_progress.is_none() && !mesh_queue.queue.is_empty() {
-        mesh_queue.in_progress = mesh_queue.queue.pop();
-        mesh_queue.meshing_state = Some(ChunkMeshingState::default());
-    }
 }
 
 pub fn on_col_unload(
     mut commands: Commands,
     mut ev_unload: EventReader<ColUnloadEvent>,
     mut chunk_ents: ResMut<ChunkEntities>,
+    mut mesh_queue: ResMut<MeshGenerationQueue>,
     mesh_query: Query<&Mesh3d>,
     mut meshes: ResMut<Assets<Mesh>>,
 ) {
     for col_ev in ev_unload.read() {
         for chunk_pos in chunks_in_col(&col_ev.0) {
+            // a chunk generated there again mustn't get the mesh of this one
+            mesh_queue.tasks.remove(&chunk_pos);
             if let Some(ent) = chunk_ents.0.remove(&chunk_pos) {
                 if let Ok(handle) = mesh_query.get(ent) {
                     meshes.remove(handle);
@@ -357,29 +306,6 @@ pub fn on_col_unload(
     }
 }
 
-fn setup_building_system(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-) {
-    // Spawn an initially invisible preview cube
-    commands.spawn((
-        BuildingPreview,
-        Mesh3d(meshes.add(Cuboid::new(0.125, 0.125, 0.125))),
-        MeshMaterial3d(materials.add(Color::srgba(1., 1., 1., 0.6))),
-        Transform::from_xyz(0.0, 0.0, 0.0),
-        Pickable::IGNORE,
-        Visibility::Hidden,
-    ));
-}
-#[derive(Resource, Default)]
-pub struct BuildingState {
-    pub current_position: Option<Vec3>,
-    pub current_normal: Option<Vec3>,
-}
-#[derive(Component)]
-pub struct BuildingPreview;
-
 #[derive(Resource)]
 pub struct ChunkEntities(pub HashMap<ChunkPos, Entity>);
 
@@ -394,8 +320,6 @@ pub struct Draw3d;
 impl Plugin for Draw3d {
     fn build(&self, app: &mut App) {
         app.add_plugins(TextureArrayPlugin)
-            .init_resource::<BuildingState>()
-            .add_systems(Startup, setup_building_system)
             .init_resource::<MeshGenerationQueue>()
             .insert_resource(ChunkEntities::new())
             .add_systems(
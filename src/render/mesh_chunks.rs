@@ -1,9 +1,11 @@
-use std::{collections::BTreeSet, time::Instant, vec};
+use std::{
+    collections::{BTreeSet, HashMap},
+    vec,
+};
 
 use bevy::{
     image::Image,
     log::info_span,
-    math::Vec3,
     prelude::Mesh,
     render::{
         mesh::{Indices, MeshVertexAttribute},
@@ -16,18 +18,15 @@ use bevy::{
 
 use super::{
     binary_greedy_meshing::{mesh_chunk, MeshData, Quad},
-    draw_chunks::ChunkMeshingState,
     texture_array::TextureMapTrait,
 };
 use crate::{
-    block::Face,
-    world::{pad_linearize, Chunk, CHUNKP_S3},
+    block::Block,
+    world::{linearize, CHUNKP_S1, CHUNK_S1},
 };
 use crate::{
-    block::{self, Block},
-    render::draw_chunks::MeshingStage,
-    utils::timeit_mut,
-    world::{linearize, ChunkPos, CHUNKP_S1, CHUNK_S1},
+    block::Face,
+    world::{column_index, Chunk, CHUNKP_S3},
 };
 
 const MASK_6: u64 = 0b111111;
@@ -49,6 +48,22 @@ const MASK_XYZ: u64 = 0b111111_111111_111111;
 pub const ATTRIBUTE_QUAD_SIZE: MeshVertexAttribute =
     MeshVertexAttribute::new("VoxelData", 48757581, VertexFormat::Float32x2);
 
+/// Quantized position, normal, color and uv of a vertex
+type VertexKey = ((i32, i32, i32), i32, i32, i32, u8, u8, u8, u8, i32, i32);
+
+/// Vertices of a chunk's mesh as they're built, shared between quads when they match
+#[derive(Default)]
+struct MeshBuffers {
+    next_vertex_index: i32,
+    vertex_map: HashMap<VertexKey, i32>,
+    all_positions: Vec<[f32; 3]>,
+    all_normals: Vec<[f32; 3]>,
+    all_indices: Vec<u16>,
+    all_uvs: Vec<[f32; 2]>,
+    all_colors: Vec<[f32; 4]>,
+    // all_quad_sizes: Vec<[f32; 2]>,
+}
+
 impl Chunk {
     pub fn voxel_data_lod(&self, lod: usize) -> Vec<u16> {
         let voxels = self.data.unpack_u16();
@@ -67,321 +82,223 @@ impl Chunk {
         res
     }
 
-    //TODO, break this to be iteratable over many frames
-    pub fn create_face_meshes(
-        &self,
-        in_progress_state: &mut ChunkMeshingState,
-    ) -> Option<(Mesh, Vec<[Vec3; 4]>)> {
+    /// Builds the render mesh of the chunk along with the quads of its collider,
+    /// None if there's nothing to see. Meant to run on a snapshot of the chunk, off the main thread.
+    pub fn create_face_meshes(&self, tints: &[[f32; 3]]) -> Option<Mesh> {
         let lod = 1;
-        let meshing_start_time = Instant::now();
         // Gathering binary greedy meshing input data
         let mesh_data_span = info_span!("mesh voxel data", name = "mesh voxel data").entered();
-        if in_progress_state.stage == MeshingStage::PrepareData {
-            in_progress_state.voxels = self.voxel_data_lod(lod);
-            in_progress_state.mesh_data = MeshData::new();
-            in_progress_state.stage = MeshingStage::Transparents;
-            if in_progress_state.is_overtime(&meshing_start_time) {
-                return None;
-            }
-        }
-
+        let voxels = self.voxel_data_lod(lod);
         mesh_data_span.exit();
-        if in_progress_state.stage == MeshingStage::Transparents {
-            in_progress_state.transparents =
-                BTreeSet::from_iter(self.palette.iter().enumerate().filter_map(|(i, block)| {
-                    if i != 0 && !block.is_opaque() {
-                        Some(i as u16)
-                    } else {
-                        None
-                    }
-                }));
-            in_progress_state.stage = MeshingStage::GreedyMeshing;
-            if in_progress_state.is_overtime(&meshing_start_time) {
-                return None;
-            }
-        }
-        if in_progress_state.stage == MeshingStage::GreedyMeshing {
-            // timeit_mut("binary greedy meshing", || {
-            mesh_chunk(
-                &in_progress_state.voxels,
-                &mut in_progress_state.mesh_data,
-                in_progress_state.transparents.clone(),
-            );
-            // });
-            in_progress_state.stage = MeshingStage::ProcessQuads;
-            if in_progress_state.is_overtime(&meshing_start_time) {
-                return None;
-            }
-        }
-
-        if in_progress_state.stage == MeshingStage::ProcessQuads {
-            // timeit_mut("Processing Quads", || {
-            // Process a batch of quads from the current face
-            let mut processed_count = 0;
-            let batch_size = in_progress_state.quad_batch_size;
-
-            // While we have faces to process
-            while in_progress_state.current_face < 6 {
-                let face_n = in_progress_state.current_face;
-                let quads = &in_progress_state.mesh_data.quads[face_n];
-
-                // Skip empty faces
-                if quads.is_empty() {
-                    in_progress_state.current_face += 1;
-                    in_progress_state.current_quad_index = 0;
-                    continue;
+        let transparents =
+            BTreeSet::from_iter(self.palette.iter().enumerate().filter_map(|(i, block)| {
+                if i != 0 && !block.is_opaque() {
+                    Some(i as u16)
+                } else {
+                    None
                 }
-
-                // Process a batch of quads from this face
-                let start_idx = in_progress_state.current_quad_index;
-                let end_idx = (start_idx + batch_size).min(quads.len());
-
-                for quad_idx in start_idx..end_idx {
-                    let quad = quads[quad_idx];
-                    let voxel_i = quad.v_type as usize;
-                    let block = self.palette[voxel_i];
-
-                    // Get mesh data for this quad
-                    let quad_mesh_data = quad_to_mesh_data(quad, block, face_n, quad_idx as u32);
-
-                    // Create a new set of indices for this quad
-                    let mut quad_indices = Vec::with_capacity(4);
-
-                    // Create physics quad vertices for collision detection
-                    let mut physics_verts = [Vec3::ZERO; 4];
-
-                    // Process each vertex of the quad
-                    for i in 0..4 {
-                        let position = quad_mesh_data.positions[i];
-                        let normal: [f32; 3] = quad_mesh_data.normals[i];
-                        let uv = quad_mesh_data.uvs[i];
-                        let color = quad_mesh_data.colors[i];
-
-                        // Convert floats to integers for hashing (with appropriate precision)
-                        let pos_key = (
-                            (position[0] * 1000.0) as i32,
-                            (position[1] * 1000.0) as i32,
-                            (position[2] * 1000.0) as i32,
-                        );
-
-                        // Create a unique key for this vertex
-                        let vertex_key = (
-                            // Position
-                            pos_key,
-                            // Normal
-                            (normal[0] * 100.0) as i32,
-                            (normal[1] * 100.0) as i32,
-                            (normal[2] * 100.0) as i32,
-                            // Color
-                            (color[0] * 255.0) as u8,
-                            (color[1] * 255.0) as u8,
-                            (color[2] * 255.0) as u8,
-                            (color[3] * 255.0) as u8,
-                            // UV
-                            (uv[0] * 1000.0) as i32,
-                            (uv[1] * 1000.0) as i32,
-                        );
-                        // Get or create the vertex index
-                        let vertex_index = match in_progress_state.vertex_map.get(&vertex_key) {
-                            Some(&index) => index,
-                            None => {
-                                // New unique vertex
-                                let index = in_progress_state.next_vertex_index;
-                                in_progress_state.vertex_map.insert(vertex_key, index);
-
-                                in_progress_state.all_positions.push(position);
-                                in_progress_state.all_normals.push(normal);
-                                in_progress_state.all_uvs.push(uv);
-                                in_progress_state.all_colors.push(color);
-                                in_progress_state.next_vertex_index += 1;
-                                index
-                            }
-                        };
-
-                        // Store the vertex index for this quad
-                        quad_indices.push(vertex_index);
-
-                        // Store physics vertex
-                        physics_verts[i] = Vec3::new(position[0], position[1], position[2]);
-                    }
-
-                    // Add the indices for this quad (two triangles)
-                    // Adjust the order based on the face type
-                    match face_n {
-                        0 => {
-                            // Face::Up
-                            in_progress_state.all_indices.extend_from_slice(&[
-                                quad_indices[2] as u16,
-                                quad_indices[0] as u16,
-                                quad_indices[1] as u16,
-                                quad_indices[2] as u16,
-                                quad_indices[3] as u16,
-                                quad_indices[0] as u16,
-                            ]);
-                        }
-                        1 => {
-                            // Face::Down
-                            in_progress_state.all_indices.extend_from_slice(&[
-                                quad_indices[0] as u16,
-                                quad_indices[2] as u16,
-                                quad_indices[1] as u16,
-                                quad_indices[0] as u16,
-                                quad_indices[3] as u16,
-                                quad_indices[2] as u16,
-                            ]);
-                        }
-                        2 => {
-                            // Face::Right
-                            in_progress_state.all_indices.extend_from_slice(&[
-                                quad_indices[1] as u16,
-                                quad_indices[0] as u16,
-                                quad_indices[2] as u16,
-                                quad_indices[0] as u16,
-                                quad_indices[3] as u16,
-                                quad_indices[2] as u16,
-                            ]);
-                        }
-                        3 => {
-                            // Face::Left
-                            in_progress_state.all_indices.extend_from_slice(&[
-                                quad_indices[0] as u16,
-                                quad_indices[1] as u16,
-                                quad_indices[3] as u16,
-                                quad_indices[3] as u16,
-                                quad_indices[1] as u16,
-                                quad_indices[2] as u16,
-                            ]);
-                        }
-                        4 => {
-                            // Face::Front
-                            in_progress_state.all_indices.extend_from_slice(&[
-                                quad_indices[1] as u16,
-                                quad_indices[0] as u16,
-                                quad_indices[2] as u16,
-                                quad_indices[2] as u16,
-                                quad_indices[0] as u16,
-                                quad_indices[3] as u16,
-                            ]);
-                        }
-                        5 => {
-                            // Face::Back
-                            in_progress_state.all_indices.extend_from_slice(&[
-                                quad_indices[1] as u16,
-                                quad_indices[2] as u16,
-                                quad_indices[0] as u16,
-                                quad_indices[2] as u16,
-                                quad_indices[3] as u16,
-                                quad_indices[0] as u16,
-                            ]);
+            }));
+        let mut mesh_data = MeshData::new();
+        mesh_chunk(&voxels, &mut mesh_data, transparents);
+
+        let mut buffers = MeshBuffers::default();
+        for (face_n, quads) in mesh_data.quads.iter().enumerate() {
+            for quad in quads.iter() {
+                let voxel_i = quad.v_type as usize;
+                let block = self.palette[voxel_i];
+
+                // Get mesh data for this quad
+                let quad_mesh_data = quad_to_mesh_data(*quad, block, face_n, tints);
+
+                // Create a new set of indices for this quad
+                let mut quad_indices = Vec::with_capacity(4);
+
+                // Process each vertex of the quad
+                for i in 0..4 {
+                    let position = quad_mesh_data.positions[i];
+                    let normal: [f32; 3] = quad_mesh_data.normals[i];
+                    let uv = quad_mesh_data.uvs[i];
+                    let color = quad_mesh_data.colors[i];
+
+                    // Convert floats to integers for hashing (with appropriate precision)
+                    let pos_key = (
+                        (position[0] * 1000.0) as i32,
+                        (position[1] * 1000.0) as i32,
+                        (position[2] * 1000.0) as i32,
+                    );
+
+                    // Create a unique key for this vertex
+                    let vertex_key = (
+                        // Position
+                        pos_key,
+                        // Normal
+                        (normal[0] * 100.0) as i32,
+                        (normal[1] * 100.0) as i32,
+                        (normal[2] * 100.0) as i32,
+                        // Color
+                        (color[0] * 255.0) as u8,
+                        (color[1] * 255.0) as u8,
+                        (color[2] * 255.0) as u8,
+                        (color[3] * 255.0) as u8,
+                        // UV
+                        (uv[0] * 1000.0) as i32,
+                        (uv[1] * 1000.0) as i32,
+                    );
+                    // Get or create the vertex index
+                    let vertex_index = match buffers.vertex_map.get(&vertex_key) {
+                        Some(&index) => index,
+                        None => {
+                            // New unique vertex
+                            let index = buffers.next_vertex_index;
+                            buffers.vertex_map.insert(vertex_key, index);
+
+                            buffers.all_positions.push(position);
+                            buffers.all_normals.push(normal);
+                            buffers.all_uvs.push(uv);
+                            buffers.all_colors.push(color);
+                            buffers.next_vertex_index += 1;
+                            index
                         }
-                        _ => {}
-                    }
+                    };
 
-                    in_progress_state.all_physics_quads.push(physics_verts);
-                    processed_count += 1;
+                    // Store the vertex index for this quad
+                    quad_indices.push(vertex_index);
                 }
 
-                // Update the quad index
-                in_progress_state.current_quad_index = end_idx;
-
-                // If we've completed this face, move to the next
-                if in_progress_state.current_quad_index >= quads.len() {
-                    in_progress_state.current_face += 1;
-                    in_progress_state.current_quad_index = 0;
-                }
-
-                // Check if we've processed enough for this frame
-                if processed_count >= batch_size
-                    || in_progress_state.is_overtime(&meshing_start_time)
-                {
-                    return None;
+                // Add the indices for this quad (two triangles)
+                // Adjust the order based on the face type
+                match face_n {
+                    0 => {
+                        // Face::Up
+                        buffers.all_indices.extend_from_slice(&[
+                            quad_indices[2] as u16,
+                            quad_indices[0] as u16,
+                            quad_indices[1] as u16,
+                            quad_indices[2] as u16,
+                            quad_indices[3] as u16,
+                            quad_indices[0] as u16,
+                        ]);
+                    }
+                    1 => {
+                        // Face::Down
+                        buffers.all_indices.extend_from_slice(&[
+                            quad_indices[0] as u16,
+                            quad_indices[2] as u16,
+                            quad_indices[1] as u16,
+                            quad_indices[0] as u16,
+                            quad_indices[3] as u16,
+                            quad_indices[2] as u16,
+                        ]);
+                    }
+                    2 => {
+                        // Face::Right
+                        buffers.all_indices.extend_from_slice(&[
+                            quad_indices[1] as u16,
+                            quad_indices[0] as u16,
+                            quad_indices[2] as u16,
+                            quad_indices[0] as u16,
+                            quad_indices[3] as u16,
+                            quad_indices[2] as u16,
+                        ]);
+                    }
+                    3 => {
+                        // Face::Left
+                        buffers.all_indices.extend_from_slice(&[
+                            quad_indices[0] as u16,
+                            quad_indices[1] as u16,
+                            quad_indices[3] as u16,
+                            quad_indices[3] as u16,
+                            quad_indices[1] as u16,
+                            quad_indices[2] as u16,
+                        ]);
+                    }
+                    4 => {
+                        // Face::Front
+                        buffers.all_indices.extend_from_slice(&[
+                            quad_indices[1] as u16,
+                            quad_indices[0] as u16,
+                            quad_indices[2] as u16,
+                            quad_indices[2] as u16,
+                            quad_indices[0] as u16,
+                            quad_indices[3] as u16,
+                        ]);
+                    }
+                    5 => {
+                        // Face::Back
+                        buffers.all_indices.extend_from_slice(&[
+                            quad_indices[1] as u16,
+                            quad_indices[2] as u16,
+                            quad_indices[0] as u16,
+                            quad_indices[2] as u16,
+                            quad_indices[3] as u16,
+                            quad_indices[0] as u16,
+                        ]);
+                    }
+                    _ => {}
                 }
             }
-
-            // If we've processed all faces, move to finalization
-            if in_progress_state.current_face >= 6 {
-                in_progress_state.stage = MeshingStage::Finalize;
-            }
-
-            if in_progress_state.is_overtime(&meshing_start_time) {
-                return None;
-            }
         }
-        if in_progress_state.stage == MeshingStage::Finalize {
-            in_progress_state.stage = MeshingStage::Complete;
-            // If we have no vertices, return None
-            if in_progress_state.all_positions.is_empty() {
-                in_progress_state.is_empty = true;
-                return None;
-            }
 
-            // Create the combined render mesh with standard attributes
-            let mut render_mesh = Mesh::new(
-                PrimitiveTopology::TriangleList,
-                RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
-            );
-            render_mesh.insert_attribute(
-                Mesh::ATTRIBUTE_POSITION,
-                in_progress_state.all_positions.clone(),
-            );
-            render_mesh.insert_attribute(
-                Mesh::ATTRIBUTE_NORMAL,
-                in_progress_state.all_normals.clone(),
-            );
-            render_mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, in_progress_state.all_uvs.clone());
-            render_mesh
-                .insert_attribute(Mesh::ATTRIBUTE_COLOR, in_progress_state.all_colors.clone());
-            // render_mesh.insert_attribute(ATTRIBUTE_QUAD_SIZE, all_quad_sizes);
-            render_mesh.insert_indices(Indices::U16(in_progress_state.all_indices.clone()));
-            return Some((render_mesh, in_progress_state.all_physics_quads.clone()));
+        // If we have no vertices, return None
+        if buffers.all_positions.is_empty() {
+            return None;
         }
-        None
-    }
-
-    pub fn create_ao_texture_data(&self) -> Image {
-        let dim = CHUNKP_S1;
 
-        // Calculate how many u32s we need (CHUNKP_S3 / 32, rounded up)
-        let u32_count = (CHUNKP_S3 + 31) / 32;
-        let mut texture_data = vec![0u32; u32_count];
-
-        let voxels = self.data.unpack_u16();
-
-        for y in 0..dim {
-            for z in 0..dim {
-                for x in 0..dim {
-                    let xyz = linearize(x, y, z);
-
-                    if voxels[xyz] != 0 {
-                        // Calculate which u32 and which bit within that u32
-                        let u32_index = xyz / 32;
-                        let bit_position = xyz % 32;
+        // Create the combined render mesh with standard attributes
+        let mut render_mesh = Mesh::new(
+            PrimitiveTopology::TriangleList,
+            RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
+        );
+        render_mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, buffers.all_positions);
+        render_mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, buffers.all_normals);
+        render_mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, buffers.all_uvs);
+        render_mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, buffers.all_colors);
+        // render_mesh.insert_attribute(ATTRIBUTE_QUAD_SIZE, all_quad_sizes);
+        render_mesh.insert_indices(Indices::U16(buffers.all_indices));
+        Some(render_mesh)
+    }
+}
 
-                        // Set the appropriate bit
-                        texture_data[u32_index] |= 1 << bit_position;
-                    }
-                }
+/// AO texture of a chunk from its solid occupancy masks (see `Occupancy`),
+/// cheap enough to build next to the mesh
+pub fn create_ao_texture_data(solid: &[u64]) -> Image {
+    let dim = CHUNKP_S1;
+
+    // Calculate how many u32s we need (CHUNKP_S3 / 32, rounded up)
+    let u32_count = CHUNKP_S3.div_ceil(32);
+    let mut texture_data = vec![0u32; u32_count];
+
+    // Reading the occupancy masks avoids unpacking the whole chunk
+    for x in 0..dim {
+        for z in 0..dim {
+            let mut column = solid[column_index(x, z)];
+            while column != 0 {
+                let y = column.trailing_zeros() as usize;
+                column &= column - 1;
+                let xyz = linearize(x, y, z);
+                // Calculate which u32 and which bit within that u32
+                texture_data[xyz / 32] |= 1 << (xyz % 32);
             }
         }
+    }
 
-        // Convert u32 array to bytes using bytemuck
-        let bytes = bytemuck::cast_slice(&texture_data).to_vec();
+    // Convert u32 array to bytes using bytemuck
+    let bytes = bytemuck::cast_slice(&texture_data).to_vec();
 
-        // Need to adjust dimensions to account for the packing
-        let width = (dim + 31) / 32; // Width in terms of u32s
+    // Need to adjust dimensions to account for the packing
+    let width = dim.div_ceil(32); // Width in terms of u32s
 
-        Image::new(
-            Extent3d {
-                width: width as u32,
-                height: dim as u32,
-                depth_or_array_layers: dim as u32,
-            },
-            TextureDimension::D3,
-            bytes,
-            TextureFormat::R32Uint, //R8Uint
-            RenderAssetUsages::RENDER_WORLD,
-        )
-    }
+    Image::new(
+        Extent3d {
+            width: width as u32,
+            height: dim as u32,
+            depth_or_array_layers: dim as u32,
+        },
+        TextureDimension::D3,
+        bytes,
+        TextureFormat::R32Uint, //R8Uint
+        RenderAssetUsages::RENDER_WORLD,
+    )
 }
 pub struct QuadMeshData {
     positions: Vec<[f32; 3]>,
@@ -389,7 +306,12 @@ pub struct QuadMeshData {
     uvs: Vec<[f32; 2]>,
     colors: Vec<[f32; 4]>,
 }
-pub fn quad_to_mesh_data(quad: Quad, block: Block, face_n: usize, quad_index: u32) -> QuadMeshData {
+pub fn quad_to_mesh_data(
+    quad: Quad,
+    block: Block,
+    face_n: usize,
+    tints: &[[f32; 3]],
+) -> QuadMeshData {
     // Extract components
     let x = (quad.x as f32) / 8.0;
     let y = (quad.y as f32) / 8.0;
@@ -434,7 +356,20 @@ pub fn quad_to_mesh_data(quad: Quad, block: Block, face_n: usize, quad_index: u3
 
     // Generate normals (same for all vertices of the quad)
     let normals = vec![normal; 4];
-    let colors = vec![get_color_from_block(&block, &face); 4];
+    let color = get_color_from_block(&block, &face);
+    // tinting each vertex blends biome colors across large quads
+    let colors = positions
+        .iter()
+        .map(|pos| match tint_at(tints, *pos) {
+            Some(tint) if is_tinted(&block, &face) => [
+                (color[0] * tint[0]).min(1.),
+                (color[1] * tint[1]).min(1.),
+                (color[2] * tint[2]).min(1.),
+                color[3],
+            ],
+            _ => color,
+        })
+        .collect();
 
     QuadMeshData {
         positions,
@@ -443,9 +378,31 @@ pub fn quad_to_mesh_data(quad: Quad, block: Block, face_n: usize, quad_index: u3
         colors,
     }
 }
+fn is_tinted(block: &Block, face: &Face) -> bool {
+    block.is_foliage() || (*block == Block::Grass && *face == Face::Up)
+}
+
+/// Biome tint at a vertex (in chunk local world units)
+fn tint_at(tints: &[[f32; 3]], [x, _, z]: [f32; 3]) -> Option<[f32; 3]> {
+    if tints.is_empty() {
+        return None;
+    }
+    let x = ((x * 8.) as usize).min(CHUNK_S1 - 1);
+    let z = ((z * 8.) as usize).min(CHUNK_S1 - 1);
+    Some(tints[z + x * CHUNK_S1])
+}
+
 pub fn get_color_from_block(block: &Block, face: &Face) -> [f32; 4] {
     let color_bits = match (block, face) {
         (block, _) if block.is_foliage() => 0b010_101_001,
+        (Block::Grass, Face::Up) => 0b011_110_010,
+        (Block::Grass | Block::Dirt, _) => 0b101_011_001,
+        (Block::Sand, _) => 0b111_110_100,
+        (Block::Stone, _) => 0b100_100_100,
+        (Block::IronOre, _) => 0b110_100_011,
+        (Block::GoldOre, _) => 0b111_110_001,
+        (Block::OakLog | Block::SpruceLog | Block::BirchLog, _) => 0b011_010_001,
+        (Block::Water, _) => 0b001_011_110,
         _ => 0b111_111_111,
     };
 
@@ -11,6 +11,7 @@ pub mod texture_array;
 
 mod texture_load;
 use bevy::prelude::Plugin;
+pub use mesh_chunks::get_color_from_block;
 pub use texture_load::*;
 
 pub struct Render;
@@ -12,10 +12,15 @@ use bevy::{
 use bevy_egui::{egui, EguiContexts, EguiGlobalSettings, EguiUserTextures};
 
 use crate::{
-    block::Block, interactions::place::{PlaceBlockEvent, PlaceDestination}, render::{
-        camera::Y_CAM_SPEED,
-        draw_chunks::{BuildingPreview, BuildingState, WorldMesh},
-    }, ui::{CameraOrbit, CameraSettings, CameraSmoothing}, utils::{lerp, INITIAL_FOV}, world::{pos3d::Pos3d, CHUNK_S1}
+    block::{Block, Face},
+    interactions::{
+        place::{PlaceBlockEvent, PlaceDestination},
+        target::{Targeting, TARGET_REACH},
+    },
+    render::{camera::Y_CAM_SPEED, draw_chunks::WorldMesh},
+    ui::{CameraOrbit, CameraSettings, CameraSmoothing},
+    utils::{lerp, INITIAL_FOV},
+    world::{pos3d::Pos3d, BlockPos, BlockRayCastHit, VoxelWorld, CHUNK_S1},
 };
 
 use super::builder_chunk;
@@ -313,8 +318,41 @@ pub fn create_area(
     builder_settings.back_panels = Some([neg_z, z, neg_y, y, neg_x, x]);
 }
 
+/// Targets the inside of the builder box wall the ray exits through,
+/// so blocks can be placed in an empty builder.
+fn builder_wall_target(ray: Ray3d, builder_size: f32) -> Option<BlockRayCastHit> {
+    let dir = *ray.direction;
+    let t1 = (BUILDER_CHUNK_POS_V3 - ray.origin) / dir;
+    let t2 = (BUILDER_CHUNK_POS_V3 + Vec3::splat(builder_size) - ray.origin) / dir;
+    let t_near = t1.min(t2).max_element().max(0.);
+    let t_far_axes = t1.max(t2);
+    let t_far = t_far_axes.min_element();
+    if t_far < t_near {
+        return None;
+    }
+    let axis = (0..3).find(|&axis| t_far_axes[axis] == t_far).unwrap_or(0);
+    // the wall's normal points back inside the box
+    let face = match (axis, dir[axis] > 0.) {
+        (0, true) => Face::Left,
+        (0, false) => Face::Right,
+        (1, true) => Face::Down,
+        (1, false) => Face::Up,
+        (2, true) => Face::Back,
+        _ => Face::Front,
+    };
+    let point = ray.origin + dir * t_far;
+    let [nx, ny, nz] = face.n();
+    // nudge the point inside to get the voxel right against the wall
+    let place_pos = BlockPos::from(point - dir * 0.001);
+    Some(BlockRayCastHit {
+        pos: place_pos + (-nx, -ny, -nz),
+        face,
+        distance: t_far,
+        place_pos,
+    })
+}
+
 pub fn render_to_image_example_system(
-    mesh_pickable_query: Query<(), With<WorldMesh>>,
     cube_preview_image: Res<EditorRenderTexture>,
     mut query: Query<(
         &GlobalTransform,
@@ -325,9 +363,8 @@ pub fn render_to_image_example_system(
     )>,
     mut builder_settings: ResMut<BuilderSettings>,
     mut contexts: EguiContexts,
-    mut building_state: ResMut<BuildingState>,
-    mut ray_cast: MeshRayCast,
-    mut preview_query: Query<(&mut Transform, &mut Visibility), With<BuildingPreview>>,
+    mut targeting: Targeting,
+    world: Res<VoxelWorld>,
     mut place_events: EventWriter<PlaceBlockEvent>,
 ) -> Result {
     let cube_preview_texture_id = contexts.image_id(&cube_preview_image).unwrap();
@@ -389,54 +426,32 @@ pub fn render_to_image_example_system(
                             camera_global_transform,
                             Vec2::new(normalized_x, normalized_y),
                         ) {
-                            let visibility = RayCastVisibility::Any;
-                            let filter = |entity| mesh_pickable_query.contains(entity);
-                            let settings = MeshRayCastSettings::default()
-                                .with_filter(&filter)
-                                .with_visibility(visibility);
-
-                            // Cast the ray with the settings
-                            if let Some(hit) = ray_cast.cast_ray(ray, &settings).first() {
-
-                                let world_position = hit.1.point + hit.1.normal *0.01;
-                            
-                                let voxel_size = 0.125;
-                                let half_voxel_size = voxel_size / 2.0 ;
-
-                                let target_voxel_pos = Vec3::new(
-                                    (world_position.x / voxel_size).floor()
-                                        * voxel_size
-                                        + half_voxel_size,
-                                    (world_position.y / voxel_size).floor()
-                                        * voxel_size
-                                        + half_voxel_size,
-                                    (world_position.z / voxel_size).floor()
-                                        * voxel_size
-                                        + half_voxel_size,
-                                );
-                                if let Ok((mut transform, mut visibility)) =
-                                    preview_query.single_mut()
-                                {
-                                    transform.translation = target_voxel_pos;
-                                    building_state.current_position = Some(target_voxel_pos);
-                                    *visibility = Visibility::Visible;
-                                }
-                            }
+                            let builder_size = builder_settings.chunk_size as f32 / 8.;
+                            // the walls hide whatever is behind them
+                            let hit = [
+                                world.raycast(ray.origin, *ray.direction, TARGET_REACH),
+                                builder_wall_target(ray, builder_size),
+                            ]
+                            .into_iter()
+                            .flatten()
+                            .min_by(|a, b| a.distance.total_cmp(&b.distance));
+                            targeting.set(hit, PlaceDestination::Builder);
                         }
                     }
 
-                    if i.pointer.primary_released() {
-                        if let Some(pos) = building_state.current_position {
-                            let p: Pos3d<1> = Pos3d {
-                                x: (pos.x * 8.) as i32,
-                                y: (pos.y * 8.) as i32,
-                                z: (pos.z * 8.) as i32,
-                            };
+                    if let Some(target) = targeting.state.target {
+                        if i.pointer.primary_released() {
                             place_events.write(PlaceBlockEvent {
-                                pos: p,
+                                pos: target.place_pos,
                                 block: Block::Stone,
                                 destination: PlaceDestination::Builder,
                             });
+                        } else if i.pointer.button_released(egui::PointerButton::Middle) {
+                            place_events.write(PlaceBlockEvent {
+                                pos: target.pos,
+                                block: Block::Air,
+                                destination: PlaceDestination::Builder,
+                            });
                         }
                     }
                     let delta = i.raw_scroll_delta;
@@ -0,0 +1,22 @@
+use crate::gen::{
+    map::{map, MapOptions},
+    pregen::{pregen, PregenOptions},
+    verify::{verify, VerifyOptions},
+};
+
+/// Runs the command given on the command line if there's one, instead of the game.
+/// Returns false when there's none.
+pub fn run_command() -> bool {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("pregen") => PregenOptions::from_args(&args[1..]).and_then(|options| pregen(&options)),
+        Some("map") => MapOptions::from_args(&args[1..]).and_then(|options| map(&options)),
+        Some("verify") => VerifyOptions::from_args(&args[1..]).and_then(|options| verify(&options)),
+        _ => return false,
+    };
+    if let Err(err) = result {
+        eprintln!("{}: {:#}", args[0], err);
+        std::process::exit(1);
+    }
+    true
+}
@@ -1,3 +1,5 @@
+mod cli;
+
 use std::time::Duration;
 
 use bevy::{
@@ -29,6 +31,10 @@ use crate::{interactions::PlayerInteractionsPlugin, render};
 use crate::{scenes::ScenesPlugin, sounds};
 
 pub fn create_app() {
+    // headless commands like pregen don't open a window
+    if cli::run_command() {
+        return;
+    }
     let mut app = App::new();
     // #[cfg(not(feature = "web"))]
     app.add_plugins(FpsOverlayPlugin {
@@ -5,6 +5,7 @@ pub mod ranges;
 mod utils;
 use crate::utils::math::counter::Counter;
 pub use closest::*;
+pub(crate) use utils::range_from_str;
 use std::fmt::Debug;
 
 pub fn print_coverage<const D: usize, E: Clone + PartialEq + Debug>(
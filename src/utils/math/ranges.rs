@@ -1,6 +1,6 @@
 use anyhow::{bail, Result};
 use itertools::Itertools;
-use std::{ops::Range, str::FromStr};
+use std::{io::Read, ops::Range, str::FromStr};
 
 use super::{
     utils::{range_from_str, RangesUtil},
@@ -27,8 +27,14 @@ impl<const D: usize, E: Clone> ClosestTrait<D, E> for Vec<([Range<f32>; D], E)>
 }
 
 pub fn from_csv<const D: usize, E: FromStr>(path: &str) -> Result<Vec<([Range<f32>; D], E)>> {
+    from_csv_reader(std::fs::File::open(path)?)
+}
+
+pub fn from_csv_reader<const D: usize, E: FromStr>(
+    reader: impl Read,
+) -> Result<Vec<([Range<f32>; D], E)>> {
     let mut res = Vec::new();
-    let mut reader = csv::Reader::from_path(path)?;
+    let mut reader = csv::Reader::from_reader(reader);
     for record in reader.records() {
         let record = record?;
         let Ok(elem) = E::from_str(&record[0]) else {
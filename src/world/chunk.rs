@@ -8,7 +8,7 @@ use super::{
 use itertools::Itertools;
 use packed_uints::{PackedEnum, PackedUints};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Chunk {
     pub data: PackedUints,
     pub palette: Palette<Block>,
@@ -70,6 +70,24 @@ impl Chunk {
         (&self.palette[0], 0)
     }
 
+    /// Local y of the highest non-air and highest opaque voxels at x,z
+    pub fn tops(&self, (x, z): ColedPos) -> (Option<usize>, Option<usize>) {
+        let mut top = None;
+        for y in (0..CHUNK_S1).rev() {
+            let block = &self.palette[self.data.get(pad_linearize(x, y, z))];
+            if *block == Block::Air {
+                continue;
+            }
+            if top.is_none() {
+                top = Some(y);
+            }
+            if block.is_opaque() {
+                return (top, Some(y));
+            }
+        }
+        (top, None)
+    }
+
     pub fn set_if_empty(&mut self, (x, y, z): ChunkedPos, block: Block) -> bool {
         let idx = pad_linearize(x, y, z);
         if self.palette[self.data.get(idx)] != Block::Air {
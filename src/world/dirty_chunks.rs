@@ -0,0 +1,146 @@
+use super::{ChunkPos, ColPos, ViewFocus};
+use crate::scenes::builder::systems::BUILDER_CHUNK_POS;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Changed chunks waiting to be (re)meshed, indexed by meshing priority
+/// so that picking the next chunk to mesh doesn't require scanning every chunk.
+/// Chunks being meshed are kept out of the queue until their meshing is done.
+pub struct DirtyChunks {
+    queue: BTreeSet<(u32, ChunkPos)>,
+    priorities: HashMap<ChunkPos, u32>,
+    meshing: HashSet<ChunkPos>,
+    // changed while being meshed, queued again once the meshing is done
+    deferred: HashSet<ChunkPos>,
+    center: ColPos,
+    focus: ViewFocus,
+}
+
+impl DirtyChunks {
+    pub fn new() -> Self {
+        Self {
+            queue: BTreeSet::new(),
+            priorities: HashMap::new(),
+            meshing: HashSet::new(),
+            deferred: HashSet::new(),
+            center: ColPos::default(),
+            focus: ViewFocus::default(),
+        }
+    }
+
+    fn priority(&self, chunk_pos: ChunkPos) -> u32 {
+        if chunk_pos == BUILDER_CHUNK_POS {
+            return 0;
+        }
+        self.focus.priority(self.center, chunk_pos.into())
+    }
+
+    pub fn insert(&mut self, chunk_pos: ChunkPos) {
+        if self.meshing.contains(&chunk_pos) {
+            self.deferred.insert(chunk_pos);
+            return;
+        }
+        if self.priorities.contains_key(&chunk_pos) {
+            return;
+        }
+        let priority = self.priority(chunk_pos);
+        self.priorities.insert(chunk_pos, priority);
+        self.queue.insert((priority, chunk_pos));
+    }
+
+    pub fn remove(&mut self, chunk_pos: &ChunkPos) {
+        if let Some(priority) = self.priorities.remove(chunk_pos) {
+            self.queue.remove(&(priority, *chunk_pos));
+        }
+        self.meshing.remove(chunk_pos);
+        self.deferred.remove(chunk_pos);
+    }
+
+    /// Takes the most urgent dirty chunk, it's kept out of the queue until `done_meshing` is called
+    pub fn pop(&mut self) -> Option<ChunkPos> {
+        let (_, chunk_pos) = self.queue.pop_first()?;
+        self.priorities.remove(&chunk_pos);
+        self.meshing.insert(chunk_pos);
+        Some(chunk_pos)
+    }
+
+    /// Queues the chunk again if it changed while it was meshed
+    pub fn done_meshing(&mut self, chunk_pos: ChunkPos) {
+        self.meshing.remove(&chunk_pos);
+        if self.deferred.remove(&chunk_pos) {
+            self.insert(chunk_pos);
+        }
+    }
+
+    /// Must be called when the player area changes since priorities depend on it
+    pub fn rekey(&mut self, center: ColPos, focus: ViewFocus) {
+        if center == self.center && focus == self.focus {
+            return;
+        }
+        self.center = center;
+        self.focus = focus;
+        let chunks = self.priorities.keys().copied().collect::<Vec<_>>();
+        self.queue.clear();
+        for chunk_pos in chunks {
+            let priority = self.priority(chunk_pos);
+            self.priorities.insert(chunk_pos, priority);
+            self.queue.insert((priority, chunk_pos));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::math::Vec3;
+
+    fn chunk(x: i32, z: i32) -> ChunkPos {
+        ChunkPos { x, y: 1, z }
+    }
+
+    #[test]
+    fn pops_closest_first() {
+        let mut dirty = DirtyChunks::new();
+        for chunk_pos in [chunk(3, 0), chunk(0, -1), chunk(-2, 2), chunk(0, 0)] {
+            dirty.insert(chunk_pos);
+        }
+        // inserting twice doesn't queue it twice
+        dirty.insert(chunk(3, 0));
+        let order: Vec<_> = std::iter::from_fn(|| dirty.pop()).collect();
+        assert_eq!(
+            order,
+            [chunk(0, 0), chunk(0, -1), chunk(-2, 2), chunk(3, 0)]
+        );
+    }
+
+    #[test]
+    fn rekey_follows_the_player() {
+        let mut dirty = DirtyChunks::new();
+        dirty.insert(chunk(-4, 0));
+        dirty.insert(chunk(4, 0));
+        dirty.rekey(ColPos { x: 3, z: 0 }, ViewFocus::default());
+        assert_eq!(dirty.pop(), Some(chunk(4, 0)));
+        // the chunk in front comes first at equal distance
+        let mut dirty = DirtyChunks::new();
+        dirty.insert(chunk(-3, 0));
+        dirty.insert(chunk(3, 0));
+        dirty.rekey(
+            ColPos::default(),
+            ViewFocus::new(Vec3::NEG_X, Vec3::ZERO, std::f32::consts::FRAC_PI_2),
+        );
+        assert_eq!(dirty.pop(), Some(chunk(-3, 0)));
+    }
+
+    #[test]
+    fn meshing_chunks_wait_outside_the_queue() {
+        let mut dirty = DirtyChunks::new();
+        dirty.insert(chunk(0, 0));
+        assert_eq!(dirty.pop(), Some(chunk(0, 0)));
+        // edited while meshing
+        dirty.insert(chunk(0, 0));
+        assert_eq!(dirty.pop(), None);
+        dirty.done_meshing(chunk(0, 0));
+        assert_eq!(dirty.pop(), Some(chunk(0, 0)));
+        dirty.done_meshing(chunk(0, 0));
+        assert_eq!(dirty.pop(), None);
+    }
+}
@@ -0,0 +1,118 @@
+use super::{ColedPos, CHUNK_S1, CHUNK_S2};
+use crate::block::Block;
+
+const NONE: i16 = -1;
+
+/// Highest non-air and highest opaque voxel for every x,z of a column,
+/// kept up to date on edits so that surface queries don't need to scan chunks.
+pub struct ColumnHeights {
+    top: Box<[i16]>,
+    opaque_top: Box<[i16]>,
+}
+
+fn index((x, z): ColedPos) -> usize {
+    x * CHUNK_S1 + z
+}
+
+fn to_option(y: i16) -> Option<i32> {
+    if y == NONE {
+        None
+    } else {
+        Some(y as i32)
+    }
+}
+
+impl ColumnHeights {
+    pub fn new() -> Self {
+        Self {
+            top: vec![NONE; CHUNK_S2].into_boxed_slice(),
+            opaque_top: vec![NONE; CHUNK_S2].into_boxed_slice(),
+        }
+    }
+
+    /// y of the highest non-air voxel
+    pub fn top(&self, pos: ColedPos) -> Option<i32> {
+        to_option(self.top[index(pos)])
+    }
+
+    /// y of the highest opaque voxel
+    pub fn opaque_top(&self, pos: ColedPos) -> Option<i32> {
+        to_option(self.opaque_top[index(pos)])
+    }
+
+    /// Registers that `block` was set at height y,
+    /// returns true if it removed one of the tops, in which case the caller needs to rescan the column.
+    pub fn update(&mut self, pos: ColedPos, y: i32, block: Block) -> bool {
+        let i = index(pos);
+        let y = y as i16;
+        let mut rescan = false;
+        if block == Block::Air {
+            rescan |= self.top[i] == y;
+        } else if y > self.top[i] {
+            self.top[i] = y;
+        }
+        if block.is_opaque() {
+            if y > self.opaque_top[i] {
+                self.opaque_top[i] = y;
+            }
+        } else {
+            rescan |= self.opaque_top[i] == y;
+        }
+        rescan
+    }
+
+    pub fn set(&mut self, pos: ColedPos, top: Option<i32>, opaque_top: Option<i32>) {
+        let i = index(pos);
+        self.top[i] = top.map(|y| y as i16).unwrap_or(NONE);
+        self.opaque_top[i] = opaque_top.map(|y| y as i16).unwrap_or(NONE);
+    }
+
+    /// Raises the tops if the given ones are higher, used when loading whole chunks
+    pub fn merge(&mut self, pos: ColedPos, top: Option<i32>, opaque_top: Option<i32>) {
+        let i = index(pos);
+        if let Some(y) = top {
+            self.top[i] = self.top[i].max(y as i16);
+        }
+        if let Some(y) = opaque_top {
+            self.opaque_top[i] = self.opaque_top[i].max(y as i16);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{BlockPos, BlockPos2d, VoxelWorld};
+
+    #[test]
+    fn update_raises_and_flags_removed_tops() {
+        let mut heights = ColumnHeights::new();
+        assert!(!heights.update((3, 5), 10, Block::Stone));
+        assert!(!heights.update((3, 5), 12, Block::Water));
+        assert_eq!(heights.top((3, 5)), Some(12));
+        assert_eq!(heights.opaque_top((3, 5)), Some(10));
+        // below the tops, nothing to rescan
+        assert!(!heights.update((3, 5), 4, Block::Air));
+        assert!(heights.update((3, 5), 12, Block::Air));
+        assert!(heights.update((3, 5), 10, Block::Water));
+        assert_eq!(heights.top((0, 0)), None);
+    }
+
+    #[test]
+    fn removing_the_top_rescans_the_column() {
+        let world = VoxelWorld::new();
+        let pos2d = BlockPos2d { x: 7, z: -3 };
+        for (y, block) in [(20, Block::Stone), (70, Block::Dirt), (71, Block::Water)] {
+            world.set_block(BlockPos { x: 7, y, z: -3 }, block, false);
+        }
+        assert_eq!(world.surface_height(pos2d), Some(71));
+        assert_eq!(world.opaque_height(pos2d), Some(70));
+        world.set_block(BlockPos { x: 7, y: 71, z: -3 }, Block::Air, false);
+        world.set_block(BlockPos { x: 7, y: 70, z: -3 }, Block::Air, false);
+        // found again in the chunk below
+        assert_eq!(world.surface_height(pos2d), Some(20));
+        assert_eq!(world.opaque_height(pos2d), Some(20));
+        world.set_block(BlockPos { x: 7, y: 20, z: -3 }, Block::Air, false);
+        assert_eq!(world.surface_height(pos2d), None);
+    }
+}
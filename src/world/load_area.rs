@@ -1,19 +1,89 @@
-use crate::{
-    scenes::builder::systems::BUILDER_CHUNK_POS,
-    world::{ChunkPos, ColPos, TrackedChunk},
-};
+use crate::world::ColPos;
 use bevy::prelude::*;
-use dashmap::DashMap;
 use itertools::iproduct;
 use std::{collections::HashMap, ops::RangeInclusive};
 
 #[derive(Component, Clone, Copy)]
 pub struct RenderDistance(pub u32);
 
+// how much further away a column directly behind the player is considered to be
+const BEHIND_PENALTY: f32 = 1.5;
+// priorities are fixed point so that in-view columns can be ordered within a ring
+const PRIORITY_SCALE: f32 = 16.;
+// cos of the angle the focus needs to turn before load orders get re-sorted
+const FOCUS_RESORT_COS: f32 = 0.97;
+// movement speed (in blocks/s) at which movement weighs as much as the camera
+const FOCUS_SPEED_REF: f32 = 10.;
+
+/// Horizontal direction the player is looking at/moving towards,
+/// used to generate and mesh what's in front of them first.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ViewFocus {
+    // normalized direction on the xz plane, ZERO if unknown
+    pub forward: Vec2,
+    // cos of the horizontal half fov, columns inside this cone get no penalty
+    pub half_fov_cos: f32,
+}
+
+impl Default for ViewFocus {
+    fn default() -> Self {
+        Self {
+            forward: Vec2::ZERO,
+            half_fov_cos: 1.,
+        }
+    }
+}
+
+impl ViewFocus {
+    pub fn new(cam_forward: Vec3, velocity: Vec3, horizontal_fov: f32) -> Self {
+        let cam_dir = Vec2::new(cam_forward.x, cam_forward.z).normalize_or_zero();
+        let move_dir = Vec2::new(velocity.x, velocity.z);
+        // the faster we go the more the movement direction matters (fast travel, free fly)
+        let move_weight = (move_dir.length() / FOCUS_SPEED_REF).min(2.);
+        let forward = (cam_dir + move_dir.normalize_or_zero() * move_weight).normalize_or_zero();
+        Self {
+            forward,
+            half_fov_cos: (horizontal_fov / 2.).min(std::f32::consts::PI).cos(),
+        }
+    }
+
+    pub fn differs(&self, other: &ViewFocus) -> bool {
+        if self.forward == Vec2::ZERO || other.forward == Vec2::ZERO {
+            return self.forward != other.forward;
+        }
+        self.forward.dot(other.forward) < FOCUS_RESORT_COS
+    }
+
+    /// Generation/meshing priority of a column, lower is more urgent.
+    /// Chebyshev distance to the center, stretched for columns outside of the view cone.
+    pub fn priority(&self, center: ColPos, col_pos: ColPos) -> u32 {
+        let dist = col_pos.dist(center) as f32;
+        // the columns right around the player are always needed
+        if dist <= 1. {
+            return (dist * PRIORITY_SCALE) as u32;
+        }
+        let offset = Vec2::new((col_pos.x - center.x) as f32, (col_pos.z - center.z) as f32);
+        (dist * self.penalty(offset) * PRIORITY_SCALE) as u32
+    }
+
+    /// Multiplier in [1; 1+BEHIND_PENALTY] applied to the distance of a column at `offset`
+    fn penalty(&self, offset: Vec2) -> f32 {
+        if self.forward == Vec2::ZERO || offset == Vec2::ZERO {
+            return 1.;
+        }
+        let cos = offset.normalize().dot(self.forward);
+        if cos >= self.half_fov_cos {
+            return 1.;
+        }
+        1. + BEHIND_PENALTY * (self.half_fov_cos - cos) / (1. + self.half_fov_cos)
+    }
+}
+
 #[derive(Resource, Clone)]
 pub struct PlayerArea {
     pub center: ColPos,
     pub col_dists: HashMap<ColPos, u32>,
+    pub focus: ViewFocus,
 }
 
 pub fn range_around(a: i32, dist: i32) -> RangeInclusive<i32> {
@@ -33,6 +103,7 @@ impl PlayerArea {
                     )
                 })
                 .collect(),
+            focus: ViewFocus::default(),
         }
     }
 
@@ -40,50 +111,11 @@ impl PlayerArea {
         Self {
             center: ColPos::default(),
             col_dists: HashMap::new(),
+            focus: ViewFocus::default(),
         }
     }
 
-    fn closest_change(&self, chunks: &DashMap<ChunkPos, TrackedChunk>) -> Option<ChunkPos> {
-        chunks
-            .iter()
-            .filter_map(|entry| {
-                let is_loaded = entry.value().loaded;
-                let is_changed = entry.value().changed;
-                let is_meshing = entry.value().meshing;
-                if is_changed && is_loaded && !is_meshing {
-                    Some(*entry.key())
-                } else {
-                    None
-                }
-            })
-            .min_by_key(|chunk_pos| {
-                if chunk_pos == &BUILDER_CHUNK_POS {
-                    println!("builder chunk cloest change");
-                    0
-                } else {
-                    <ColPos>::from(*chunk_pos).dist(self.center)
-                }
-            })
-    }
-
-    pub fn pop_closest_change(
-        &self,
-        chunks: &DashMap<ChunkPos, TrackedChunk>,
-    ) -> Option<(ChunkPos, u32)> {
-        let span =
-            info_span!("selecting chunk to mesh", name = "selecting chunk to mesh").entered();
-        let res = self.closest_change(chunks)?;
-        span.exit();
-        let Some(mut chunk) = chunks.get_mut(&res) else {
-            println!("couldn't get_mut chunk {:?}", res);
-            return None;
-        };
-        chunk.meshing = true;
-        Some((
-            res,
-            res.x
-                .abs_diff(self.center.x)
-                .max(res.z.abs_diff(self.center.z)),
-        ))
+    pub fn priority(&self, col_pos: ColPos) -> u32 {
+        self.focus.priority(self.center, col_pos)
     }
 }
@@ -1,8 +1,10 @@
+use crate::render::camera::MainCamera;
 use crate::scenes::builder::systems::BUILDER_CHUNK_POS;
 
 use super::BlockPos;
 use super::{
-    pos2d::Pos2d, utils::ReinsertTrait, ColPos, PlayerArea, RenderDistance, VoxelWorld, CHUNK_S1,
+    pos2d::Pos2d, utils::ReinsertTrait, ColPos, PlayerArea, RenderDistance, ViewFocus, VoxelWorld,
+    CHUNK_S1,
 };
 use bevy::prelude::*;
 use itertools::Itertools;
@@ -48,7 +50,7 @@ fn update_gen_order(
 pub struct LoadOrders {
     // { column: { player } }
     player_cols: HashMap<ColPos, HashSet<u32>>,
-    // [(column, min dist to player)]
+    // [(column, min priority for players)], sorted from last to first to generate
     pub to_generate: Arc<RwLock<Vec<(ColPos, u32)>>>,
     pub to_unload: Vec<ColPos>,
 }
@@ -102,7 +104,7 @@ impl LoadOrders {
         }
         let mut wlock: ArcRwLockWriteGuard<RawRwLock, Vec<(Pos2d<CHUNK_S1>, u32)>> =
             self.to_generate.write_arc();
-        for (col_pos, dist) in new_load_area.col_dists.iter() {
+        for col_pos in new_load_area.col_dists.keys() {
             if old_load_area.col_dists.contains_key(col_pos) {
                 continue;
             }
@@ -110,13 +112,41 @@ impl LoadOrders {
             let players = self.player_cols.entry(*col_pos).or_default();
             let is_new = players.is_empty();
             players.insert(player_id);
+            let priority = new_load_area.priority(*col_pos);
             if is_new {
-                add_gen_order(&mut wlock, *col_pos, *dist);
+                add_gen_order(&mut wlock, *col_pos, priority);
             } else {
-                update_gen_order(&mut wlock, col_pos, *dist)
+                update_gen_order(&mut wlock, col_pos, priority)
             }
         }
     }
+
+    /// Unloads every loaded column and orders it generated again, for when the generator changes
+    pub fn regenerate(&mut self, load_area: &PlayerArea) {
+        let mut wlock = self.to_generate.write_arc();
+        let pending: HashSet<ColPos> = wlock.iter().map(|(pos, _)| *pos).collect();
+        for col_pos in self.player_cols.keys() {
+            if pending.contains(col_pos)
+                || (col_pos.x == BUILDER_CHUNK_POS.x && col_pos.z == BUILDER_CHUNK_POS.z)
+            {
+                continue;
+            }
+            self.to_unload.push(*col_pos);
+            wlock.push((*col_pos, load_area.priority(*col_pos)));
+        }
+        // sorted once rather than inserting each order in place
+        wlock.sort_by(|(_, a), (_, b)| b.cmp(a));
+    }
+
+    /// Recomputes the priority of every pending generation order,
+    /// needed when the player moves or turns since priorities depend on the view
+    pub fn reprioritize(&mut self, load_area: &PlayerArea) {
+        let mut wlock = self.to_generate.write_arc();
+        for (col_pos, priority) in wlock.iter_mut() {
+            *priority = load_area.priority(*col_pos);
+        }
+        wlock.sort_by(|(_, a), (_, b)| b.cmp(a));
+    }
 }
 
 pub fn assign_load_area(
@@ -143,25 +173,64 @@ pub fn update_load_area(
         let col = ColPos::from(transform.translation);
         // we're checking before modifying to avoid triggering unnecessary Change detection
         if col != load_area.center {
-            let new_load_area = PlayerArea::new(col, *render_dist);
+            let mut new_load_area = PlayerArea::new(col, *render_dist);
+            new_load_area.focus = load_area.focus;
             col_orders.on_load_area_change(player.index(), &load_area, &new_load_area);
+            col_orders.reprioritize(&new_load_area);
             *load_area = new_load_area;
         }
     }
 }
 
+pub fn update_view_focus(
+    cam_query: Query<(&GlobalTransform, &Projection), With<MainCamera>>,
+    time: Res<Time>,
+    mut last_cam_pos: Local<Option<Vec3>>,
+    mut col_orders: ResMut<LoadOrders>,
+    mut load_area: ResMut<PlayerArea>,
+) {
+    let Ok((cam_transform, projection)) = cam_query.single() else {
+        return;
+    };
+    let translation = cam_transform.translation();
+    let velocity = match *last_cam_pos {
+        Some(last_pos) if time.delta_secs() > 0. => (translation - last_pos) / time.delta_secs(),
+        _ => Vec3::ZERO,
+    };
+    *last_cam_pos = Some(translation);
+    let horizontal_fov = match projection {
+        Projection::Perspective(persp) => 2. * ((persp.fov / 2.).tan() * persp.aspect_ratio).atan(),
+        _ => std::f32::consts::PI,
+    };
+    let focus = ViewFocus::new(*cam_transform.forward(), velocity, horizontal_fov);
+    // we're checking before modifying to avoid re-sorting the orders every frame
+    if !load_area.focus.differs(&focus) {
+        return;
+    }
+    load_area.focus = focus;
+    col_orders.reprioritize(&load_area);
+}
+
 pub fn on_render_distance_change(
     mut query: Query<(Entity, &RenderDistance), Changed<RenderDistance>>,
     mut col_orders: ResMut<LoadOrders>,
     mut load_area: ResMut<PlayerArea>,
 ) {
     for (player, render_dist) in query.iter_mut() {
-        let new_load_area = PlayerArea::new(load_area.center, *render_dist);
+        let mut new_load_area = PlayerArea::new(load_area.center, *render_dist);
+        new_load_area.focus = load_area.focus;
         col_orders.on_load_area_change(player.index(), &load_area, &new_load_area);
         *load_area = new_load_area;
     }
 }
 
+pub fn rekey_dirty_chunks(load_area: Res<PlayerArea>, world: Res<VoxelWorld>) {
+    if !load_area.is_changed() {
+        return;
+    }
+    world.dirty.lock().rekey(load_area.center, load_area.focus);
+}
+
 #[derive(Default, Resource)]
 pub struct BlockEntities(HashMap<ColPos, HashMap<(usize, i32, usize), Entity>>);
 
@@ -1,17 +1,26 @@
 mod chunk;
+mod dirty_chunks;
+mod heightmap;
 mod load_area;
 mod load_orders;
+mod occupancy;
 mod pos;
+mod queries;
 mod utils;
 mod voxel_world;
 
 use self::load_orders::{
-    assign_load_area, on_render_distance_change, process_unload_orders, update_load_area,
+    assign_load_area, on_render_distance_change, process_unload_orders, rekey_dirty_chunks,
+    update_load_area, update_view_focus,
 };
+use crate::r#gen::config::{GenConfig, GenConfigLoader};
+use crate::r#gen::presets::WorldPreset;
 use crate::r#gen::terrain_gen::{
-    process_terrain_generation, queue_terrain_generation, setup_gen_system,
+    forget_unloaded_cols, process_terrain_generation, queue_terrain_generation, reload_gen_config,
+    setup_gen_system, WorldSeed,
 };
 use crate::{agents::PlayerSpawn, gen::*};
+use bevy::asset::AssetApp;
 use bevy::ecs::schedule::IntoScheduleConfigs;
 use bevy::{
     app::Startup,
@@ -19,8 +28,10 @@ use bevy::{
     prelude::{Plugin, Update},
 };
 pub use chunk::*;
-pub use load_area::{range_around, PlayerArea, RenderDistance};
+pub use heightmap::ColumnHeights;
+pub use load_area::{range_around, PlayerArea, RenderDistance, ViewFocus};
 pub use load_orders::{BlockEntities, ColUnloadEvent, LoadOrders};
+pub use occupancy::{column_index, Occupancy};
 pub use pos::*;
 pub use voxel_world::*;
 pub const CHUNK_S1: usize = 62;
@@ -49,10 +60,14 @@ impl Plugin for GenPlugin {
         app.insert_resource(LoadOrders::new())
             .insert_resource(BlockEntities::default())
             .add_event::<ColUnloadEvent>()
+            .init_resource::<WorldSeed>()
+            .init_resource::<WorldPreset>()
+            .init_asset::<GenConfig>()
+            .init_asset_loader::<GenConfigLoader>()
             .add_systems(Startup, setup_gen_system)
             .add_systems(
                 Update,
-                (queue_terrain_generation, process_terrain_generation),
+                (queue_terrain_generation, process_terrain_generation).after(forget_unloaded_cols),
             )
             .add_systems(
                 Startup,
@@ -60,8 +75,25 @@ impl Plugin for GenPlugin {
                     .in_set(LoadAreaAssigned::Assigned)
                     .after(PlayerSpawn),
             )
-            .add_systems(Update, update_load_area)
-            .add_systems(Update, on_render_distance_change)
-            .add_systems(Update, process_unload_orders);
+            .add_systems(
+                Update,
+                (
+                    update_view_focus,
+                    update_load_area,
+                    on_render_distance_change,
+                    rekey_dirty_chunks,
+                )
+                    .chain(),
+            )
+            .add_systems(
+                Update,
+                // a new config unloads columns that must be gone before they're requested again
+                (
+                    reload_gen_config,
+                    process_unload_orders,
+                    forget_unloaded_cols,
+                )
+                    .chain(),
+            );
     }
 }
@@ -0,0 +1,183 @@
+use super::{Chunk, ChunkedPos, CHUNKP_S1, CHUNKP_S2, CHUNK_S1};
+use crate::block::Block;
+use itertools::Itertools;
+use std::simd::{num::SimdUint, u64x8};
+
+// bits 1..=62, the voxels of a padded column that belong to the chunk
+const INNER_BITS: u64 = ((1 << CHUNK_S1) - 1) << 1;
+
+/// Solid (non air), collidable (solid but not fluid) and opaque bits of a padded chunk.
+/// There's one u64 per padded (x, z) column, bit y is set if the voxel is occupied.
+/// Column indices match the lower part of `linearize`: z + x * CHUNKP_S1.
+pub struct Occupancy {
+    pub solid: Box<[u64]>,
+    pub collidable: Box<[u64]>,
+    pub opaque: Box<[u64]>,
+}
+
+pub fn column_index(x: usize, z: usize) -> usize {
+    z + x * CHUNKP_S1
+}
+
+// the rows of columns that belong to the chunk (padding excluded)
+fn inner_rows(mask: &[u64]) -> impl Iterator<Item = &[u64]> {
+    (1..CHUNKP_S1 - 1).map(|x| &mask[column_index(x, 1)..column_index(x, CHUNKP_S1 - 1)])
+}
+
+fn any_inner(mask: &[u64]) -> bool {
+    inner_rows(mask).any(|row| {
+        let mut lanes = row.chunks_exact(8);
+        let acc = lanes
+            .by_ref()
+            .fold(u64x8::splat(0), |acc, lane| acc | u64x8::from_slice(lane));
+        let rest = lanes.remainder().iter().fold(0, |acc, bits| acc | bits);
+        (acc.reduce_or() | rest) & INNER_BITS != 0
+    })
+}
+
+// which of the solid, collidable and opaque masks the block is in
+fn flags(block: Block) -> [bool; 3] {
+    [
+        block != Block::Air,
+        block.is_collidable(),
+        block.is_opaque(),
+    ]
+}
+
+fn all_set(mask: &[u64]) -> bool {
+    mask.chunks_exact(8)
+        .all(|lane| u64x8::from_slice(lane).reduce_and() == u64::MAX)
+}
+
+impl Occupancy {
+    pub fn new() -> Self {
+        Self {
+            solid: vec![0; CHUNKP_S2].into_boxed_slice(),
+            collidable: vec![0; CHUNKP_S2].into_boxed_slice(),
+            opaque: vec![0; CHUNKP_S2].into_boxed_slice(),
+        }
+    }
+
+    pub fn from_chunk(chunk: &Chunk) -> Self {
+        let mut res = Self::new();
+        let flags = chunk
+            .palette
+            .iter()
+            .map(|block| flags(*block))
+            .collect_vec();
+        for (i, voxel) in chunk.data.unpack_u16().into_iter().enumerate() {
+            let (y, column) = (i / CHUNKP_S2, i % CHUNKP_S2);
+            for (mask, set) in res.masks_mut().into_iter().zip(flags[voxel as usize]) {
+                if set {
+                    mask[column] |= 1 << y;
+                }
+            }
+        }
+        res
+    }
+
+    fn masks_mut(&mut self) -> [&mut [u64]; 3] {
+        [&mut self.solid, &mut self.collidable, &mut self.opaque]
+    }
+
+    fn set_bits(&mut self, column: usize, bits: u64, block: Block) {
+        for (mask, set) in self.masks_mut().into_iter().zip(flags(block)) {
+            if set {
+                mask[column] |= bits;
+            } else {
+                mask[column] &= !bits;
+            }
+        }
+    }
+
+    /// Takes padded coordinates
+    pub fn set(&mut self, (x, y, z): ChunkedPos, block: Block) {
+        self.set_bits(column_index(x, z), 1 << y, block);
+    }
+
+    /// Sets the voxels from bottom to top (included) of a column in one go, takes padded coordinates
+    pub fn set_yrange(&mut self, (x, z): (usize, usize), bottom: usize, top: usize, block: Block) {
+        let bits = (u64::MAX >> (63 - (top - bottom))) << bottom;
+        self.set_bits(column_index(x, z), bits, block);
+    }
+
+    /// Takes padded coordinates
+    pub fn is_collidable(&self, (x, y, z): ChunkedPos) -> bool {
+        self.collidable[column_index(x, z)] >> y & 1 != 0
+    }
+
+    /// True if none of the chunk's own voxels are solid (padding excluded)
+    pub fn is_empty(&self) -> bool {
+        !any_inner(&self.solid)
+    }
+
+    /// True if every voxel including the padding is opaque, meaning nothing in the chunk can be seen
+    pub fn is_buried(&self) -> bool {
+        all_set(&self.opaque)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::TrackedChunk;
+
+    #[test]
+    fn yrange_matches_single_sets() {
+        let mut ranged = Occupancy::new();
+        let mut single = Occupancy::new();
+        for (bottom, top, block) in [
+            (1, 62, Block::Stone),
+            (10, 20, Block::Water),
+            (15, 16, Block::Air),
+        ] {
+            ranged.set_yrange((4, 9), bottom, top, block);
+            for y in bottom..=top {
+                single.set((4, y, 9), block);
+            }
+        }
+        assert_eq!(ranged.solid, single.solid);
+        assert_eq!(ranged.collidable, single.collidable);
+        assert_eq!(ranged.opaque, single.opaque);
+        assert!(ranged.solid[column_index(4, 9)] >> 14 & 1 != 0);
+        // water isn't collidable
+        assert!(!ranged.is_collidable((4, 14, 9)) && ranged.is_collidable((4, 9, 9)));
+        let opaque = ranged.opaque[column_index(4, 9)];
+        assert!(opaque >> 14 & 1 == 0 && opaque >> 21 & 1 != 0);
+    }
+
+    #[test]
+    fn tracked_chunk_masks_match_its_blocks() {
+        let mut chunk = TrackedChunk::new();
+        assert!(chunk.occupancy.is_empty());
+        chunk.set_yrange((3, 40, 5), 30, Block::Dirt);
+        // goes below the chunk, stops at its bottom
+        chunk.set_yrange((6, 2, 6), 10, Block::Stone);
+        chunk.set((3, 20, 5), Block::Air);
+        chunk.set((3, 20, 5), Block::Water);
+        let from_blocks = Occupancy::from_chunk(&chunk);
+        assert_eq!(chunk.occupancy.solid, from_blocks.solid);
+        assert_eq!(chunk.occupancy.collidable, from_blocks.collidable);
+        assert_eq!(chunk.occupancy.opaque, from_blocks.opaque);
+        let collidable = itertools::iproduct!(1..=CHUNK_S1, 1..=CHUNK_S1, 1..=CHUNK_S1)
+            .filter(|&pos| chunk.occupancy.is_collidable(pos))
+            .count();
+        // the water doesn't count
+        assert_eq!(collidable, 30 + 3);
+        assert!(!chunk.occupancy.is_empty() && !chunk.occupancy.is_buried());
+    }
+
+    #[test]
+    fn buried_needs_the_padding() {
+        let mut occupancy = Occupancy::new();
+        for (x, z) in itertools::iproduct!(0..CHUNKP_S1, 0..CHUNKP_S1) {
+            occupancy.set_yrange((x, z), 1, CHUNK_S1, Block::Stone);
+        }
+        assert!(!occupancy.is_buried());
+        for (x, z) in itertools::iproduct!(0..CHUNKP_S1, 0..CHUNKP_S1) {
+            occupancy.set((x, 0, z), Block::Stone);
+            occupancy.set((x, CHUNKP_S1 - 1, z), Block::Stone);
+        }
+        assert!(occupancy.is_buried());
+    }
+}
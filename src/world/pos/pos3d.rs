@@ -4,7 +4,7 @@ use bevy::prelude::Vec3;
 use std::fmt::{Display, Formatter, Result};
 use std::ops::{Add, BitXor};
 
-#[derive(Clone, Copy, Eq, PartialEq, Default, Debug, Hash)]
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Default, Debug, Hash)]
 pub struct Pos3d<const U: usize> {
     pub x: i32,
     pub y: i32,
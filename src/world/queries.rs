@@ -0,0 +1,223 @@
+use super::{
+    chunked, pad_linearize, BlockPos, ChunkPos, ChunkSnapshot, ChunkedPos, VoxelWorld, CHUNK_S1,
+};
+use crate::block::{Block, BlockFamily};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+const NEIGHBOURS: [(i32, i32, i32); 6] = [
+    (-1, 0, 0),
+    (1, 0, 0),
+    (0, -1, 0),
+    (0, 1, 0),
+    (0, 0, -1),
+    (0, 0, 1),
+];
+
+/// Keeps a snapshot of the last chunk read, since queries tend to hit the same chunk many times in a row
+struct CachedReader<'a> {
+    world: &'a VoxelWorld,
+    last: Option<(ChunkPos, Option<ChunkSnapshot>)>,
+}
+
+impl<'a> CachedReader<'a> {
+    fn new(world: &'a VoxelWorld) -> Self {
+        Self { world, last: None }
+    }
+
+    fn get(&mut self, pos: BlockPos) -> Block {
+        let (chunk_pos, chunked_pos) = <(ChunkPos, ChunkedPos)>::from(pos);
+        if !matches!(&self.last, Some((last_pos, _)) if *last_pos == chunk_pos) {
+            self.last = Some((chunk_pos, self.world.snapshot(chunk_pos)));
+        }
+        match &self.last {
+            Some((_, Some(chunk))) => *chunk.get(chunked_pos),
+            _ => Block::Air,
+        }
+    }
+}
+
+// offsets at exactly chebyshev distance r
+fn shell(r: i32) -> impl Iterator<Item = (i32, i32, i32)> {
+    (-r..=r).flat_map(move |dx| {
+        (-r..=r).flat_map(move |dy| {
+            let on_edge = dx.abs() == r || dy.abs() == r;
+            let step = if on_edge || r == 0 { 1 } else { 2 * r as usize };
+            (-r..=r).step_by(step).map(move |dz| (dx, dy, dz))
+        })
+    })
+}
+
+fn ordered(a: BlockPos, b: BlockPos) -> (BlockPos, BlockPos) {
+    (
+        BlockPos::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z)),
+        BlockPos::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z)),
+    )
+}
+
+// tree felling, AI goal search and ore surveys will call these, none of them exist yet
+impl VoxelWorld {
+    /// Closest block (euclidean distance) within `radius` of `center` that satisfies `predicate`
+    #[allow(dead_code)]
+    pub fn nearest_block(
+        &self,
+        center: BlockPos,
+        radius: u32,
+        predicate: impl Fn(Block) -> bool,
+    ) -> Option<(BlockPos, Block)> {
+        let mut reader = CachedReader::new(self);
+        let max_dist2 = (radius * radius) as i32;
+        let mut best: Option<(i32, BlockPos, Block)> = None;
+        for r in 0..=radius as i32 {
+            for (dx, dy, dz) in shell(r) {
+                let dist2 = dx * dx + dy * dy + dz * dz;
+                if dist2 > max_dist2 || best.is_some_and(|(best_dist2, ..)| dist2 >= best_dist2) {
+                    continue;
+                }
+                let pos = center + (dx, dy, dz);
+                let block = reader.get(pos);
+                if predicate(block) {
+                    best = Some((dist2, pos, block));
+                }
+            }
+            // everything in the next shells is at least r+1 away
+            if best.is_some_and(|(best_dist2, ..)| best_dist2 <= (r + 1) * (r + 1)) {
+                break;
+            }
+        }
+        best.map(|(_, pos, block)| (pos, block))
+    }
+
+    /// Blocks of `family` connected to `start` (6-neighbourhood), in breadth first order.
+    /// Stops after `limit` blocks, the bool is true if the limit was hit.
+    #[allow(dead_code)]
+    pub fn flood_fill(
+        &self,
+        start: BlockPos,
+        family: BlockFamily,
+        limit: usize,
+    ) -> (Vec<BlockPos>, bool) {
+        let mut reader = CachedReader::new(self);
+        let mut res = Vec::new();
+        let mut seen = HashSet::from([start]);
+        let mut queue = VecDeque::from([start]);
+        while let Some(pos) = queue.pop_front() {
+            if !reader.get(pos).families().contains(&family) {
+                continue;
+            }
+            if res.len() >= limit {
+                return (res, true);
+            }
+            res.push(pos);
+            for offset in NEIGHBOURS {
+                let next = pos + offset;
+                if seen.insert(next) {
+                    queue.push_back(next);
+                }
+            }
+        }
+        (res, false)
+    }
+
+    /// Number of blocks of each type in the region between `a` and `b` (inclusive)
+    #[allow(dead_code)]
+    pub fn count_blocks(&self, a: BlockPos, b: BlockPos) -> HashMap<Block, usize> {
+        let (min, max) = ordered(a, b);
+        let (min_cx, min_dx) = chunked(min.x);
+        let (min_cy, min_dy) = chunked(min.y);
+        let (min_cz, min_dz) = chunked(min.z);
+        let (max_cx, max_dx) = chunked(max.x);
+        let (max_cy, max_dy) = chunked(max.y);
+        let (max_cz, max_dz) = chunked(max.z);
+        // local range of the region in the chunk at coord c along an axis
+        let local = |c: i32, min_c: i32, min_d: usize, max_c: i32, max_d: usize| {
+            let lo = if c == min_c { min_d } else { 0 };
+            let hi = if c == max_c { max_d } else { CHUNK_S1 - 1 };
+            lo..=hi
+        };
+        let mut res = HashMap::new();
+        for cx in min_cx..=max_cx {
+            for cy in min_cy..=max_cy {
+                for cz in min_cz..=max_cz {
+                    let xs = local(cx, min_cx, min_dx, max_cx, max_dx);
+                    let ys = local(cy, min_cy, min_dy, max_cy, max_dy);
+                    let zs = local(cz, min_cz, min_dz, max_cz, max_dz);
+                    let Some(chunk) = self.snapshot(ChunkPos::new(cx, cy, cz)) else {
+                        let volume = xs.count() * ys.count() * zs.count();
+                        *res.entry(Block::Air).or_default() += volume;
+                        continue;
+                    };
+                    // count palette indices first, it's much cheaper than hashing blocks
+                    let mut counts = vec![0; chunk.palette.len()];
+                    for y in ys {
+                        for x in xs.clone() {
+                            for z in zs.clone() {
+                                counts[chunk.data.get(pad_linearize(x, y, z))] += 1;
+                            }
+                        }
+                    }
+                    for (i, count) in counts.into_iter().enumerate() {
+                        if count > 0 {
+                            *res.entry(chunk.palette[i]).or_default() += count;
+                        }
+                    }
+                }
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a line of logs crossing the border between chunks x=0 and x=1, with one more past a gap
+    fn logs() -> VoxelWorld {
+        let world = VoxelWorld::new();
+        for x in (58..=66).chain([68]) {
+            world.set_block(BlockPos::new(x, 5, 0), Block::OakLog, false);
+        }
+        world.set_block(BlockPos::new(60, 5, -3), Block::IronOre, false);
+        world
+    }
+
+    #[test]
+    fn nearest_block_across_chunks() {
+        let world = logs();
+        let center = BlockPos::new(60, 5, -1);
+        let ore = world.nearest_block(center, 4, |block| block == Block::IronOre);
+        assert_eq!(ore, Some((BlockPos::new(60, 5, -3), Block::IronOre)));
+        let log = world.nearest_block(center, 4, |block| block == Block::OakLog);
+        assert_eq!(log, Some((BlockPos::new(60, 5, 0), Block::OakLog)));
+        assert_eq!(
+            world.nearest_block(center, 4, |block| block == Block::Stone),
+            None
+        );
+        assert_eq!(
+            world.nearest_block(center, 0, |block| block != Block::Air),
+            None
+        );
+    }
+
+    #[test]
+    fn flood_fill_stops_at_gaps_and_limit() {
+        let world = logs();
+        let start = BlockPos::new(58, 5, 0);
+        let (wood, limited) = world.flood_fill(start, BlockFamily::Wood, 100);
+        assert_eq!((wood.len(), limited), (9, false));
+        assert!(!wood.contains(&BlockPos::new(68, 5, 0)));
+        let (wood, limited) = world.flood_fill(start, BlockFamily::Wood, 4);
+        assert_eq!((wood.len(), limited), (4, true));
+        let (none, _) = world.flood_fill(BlockPos::new(58, 6, 0), BlockFamily::Wood, 100);
+        assert!(none.is_empty());
+    }
+
+    #[test]
+    fn count_blocks_in_any_corner_order() {
+        let world = logs();
+        let counts = world.count_blocks(BlockPos::new(66, 6, 1), BlockPos::new(56, 4, -1));
+        assert_eq!(counts[&Block::OakLog], 9);
+        assert_eq!(counts[&Block::Air], 11 * 3 * 3 - 9);
+        assert_eq!(counts.len(), 2);
+    }
+}
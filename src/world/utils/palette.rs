@@ -1,6 +1,6 @@
 use std::{collections::HashMap, hash::Hash, ops::Index, slice::Iter};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Palette<E: Hash + Eq + PartialEq + Clone> {
     leftmap: HashMap<E, usize>,
     rightmap: Vec<E>,
@@ -1,39 +1,107 @@
-use crate::block::Block;
+use crate::block::{Block, Face};
+use crate::r#gen::biomes::{Biome, ColumnBiomes};
 
 use super::{
-    chunked, pos2d::chunks_in_col, BlockPos, BlockPos2d, Chunk, ChunkPos, ChunkedPos, ColPos,
-    ColedPos, CHUNKP_S1, CHUNK_S1, CHUNK_S1I, MAX_HEIGHT, Y_CHUNKS,
+    dirty_chunks::DirtyChunks, pos2d::chunks_in_col, BlockPos, BlockPos2d, Chunk, ChunkPos,
+    ChunkedPos, ColPos, ColedPos, ColumnHeights, Occupancy, CHUNKP_S1, CHUNK_S1, CHUNK_S1I,
+    MAX_HEIGHT, Y_CHUNKS,
 };
 
 use bevy::{
     asset::Handle,
     image::Image,
+    log::info_span,
     prelude::{Resource, Vec3},
 };
 use dashmap::DashMap;
-use std::{
-    ops::{Deref, DerefMut},
-    sync::Arc,
-};
+use parking_lot::Mutex;
+use std::{ops::Deref, sync::Arc};
+
+/// A version of a chunk's blocks that doesn't change as the chunk is edited,
+/// so it can be read without holding on to the world.
+/// Cheap to take, edits copy the blocks only while a snapshot still has them.
+#[derive(Clone)]
+pub struct ChunkSnapshot {
+    pub revision: u64,
+    pub chunk: Arc<Chunk>,
+}
+
+impl Deref for ChunkSnapshot {
+    type Target = Chunk;
+
+    fn deref(&self) -> &Self::Target {
+        &self.chunk
+    }
+}
 
 pub struct TrackedChunk {
-    chunk: Chunk,
+    // copied on write, only while snapshots share it
+    chunk: Arc<Chunk>,
+    pub occupancy: Occupancy,
     pub ao_image: Option<Handle<Image>>,
     pub loaded: bool,
-    pub meshing: bool,
     pub changed: bool,
+    // bumped on every edit, tells meshes of an older version of the chunk apart
+    pub revision: u64,
 }
 
 impl TrackedChunk {
     pub fn new() -> Self {
         Self {
-            chunk: Chunk::new(),
+            chunk: Arc::new(Chunk::new()),
+            occupancy: Occupancy::new(),
             ao_image: None,
             loaded: true,
-            meshing: false,
             changed: true,
+            revision: 0,
         }
     }
+
+    pub fn from_chunk(chunk: Chunk) -> Self {
+        Self {
+            occupancy: Occupancy::from_chunk(&chunk),
+            chunk: Arc::new(chunk),
+            ao_image: None,
+            loaded: true,
+            changed: true,
+            revision: 0,
+        }
+    }
+
+    pub fn snapshot(&self) -> ChunkSnapshot {
+        ChunkSnapshot {
+            revision: self.revision,
+            chunk: self.chunk.clone(),
+        }
+    }
+
+    /// The blocks for an edit, copied first if a snapshot has them
+    fn chunk_mut(&mut self) -> &mut Chunk {
+        self.revision += 1;
+        Arc::make_mut(&mut self.chunk)
+    }
+
+    // Edits only go through the setters below (it derefs to Chunk for reads only)
+    // to keep the occupancy masks and revision in sync
+
+    pub fn set(&mut self, (x, y, z): ChunkedPos, block: Block) {
+        self.chunk_mut().set((x, y, z), block);
+        self.occupancy.set((x + 1, y + 1, z + 1), block);
+    }
+
+    pub fn set_no_padding(&mut self, pos: ChunkedPos, block: Block) {
+        self.chunk_mut().set_no_padding(pos, block);
+        self.occupancy.set(pos, block);
+    }
+
+    /// Sets the blocks from top-height to top (included), stopping at the bottom of the chunk
+    pub fn set_yrange(&mut self, (x, top, z): ChunkedPos, height: usize, block: Block) {
+        let bottom = top.saturating_sub(height);
+        self.chunk_mut()
+            .set_yrange((x, top, z), top - bottom, block);
+        self.occupancy
+            .set_yrange((x + 1, z + 1), bottom + 1, top + 1, block);
+    }
 }
 
 impl Deref for TrackedChunk {
@@ -44,58 +112,88 @@ impl Deref for TrackedChunk {
     }
 }
 
-impl DerefMut for TrackedChunk {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.chunk
-    }
-}
+// voxels are 1/8 of a world unit
+const VOXELS_PER_UNIT: f32 = 8.;
 
+#[derive(Debug, Clone, Copy)]
 pub struct BlockRayCastHit {
     pub pos: BlockPos,
-    pub normal: Vec3,
+    // face of the hit block the ray entered through
+    pub face: Face,
+    // from the ray's origin, in world units
+    pub distance: f32,
+    // where a block placed against the hit face would go
+    pub place_pos: BlockPos,
 }
 
 impl PartialEq for BlockRayCastHit {
     fn eq(&self, other: &Self) -> bool {
-        self.pos == other.pos
+        self.pos == other.pos && self.face == other.face
     }
 }
 
 #[derive(Resource)]
 pub struct VoxelWorld {
     pub chunks: Arc<DashMap<ChunkPos, TrackedChunk>>,
+    // NOTE: never lock this while holding a guard on `chunks`
+    pub dirty: Arc<Mutex<DirtyChunks>>,
+    pub heights: Arc<DashMap<ColPos, ColumnHeights>>,
+    // filled by the generator, absent for columns it didn't create
+    pub biomes: Arc<DashMap<ColPos, ColumnBiomes>>,
 }
 
 impl VoxelWorld {
     pub fn new() -> Self {
         VoxelWorld {
             chunks: Arc::new(DashMap::new()),
+            dirty: Arc::new(Mutex::new(DirtyChunks::new())),
+            heights: Arc::new(DashMap::new()),
+            biomes: Arc::new(DashMap::new()),
         }
     }
 
     pub fn new_with(chunks: Arc<DashMap<ChunkPos, TrackedChunk>>) -> Self {
-        VoxelWorld { chunks }
+        let mut dirty = DirtyChunks::new();
+        for entry in chunks.iter() {
+            if entry.changed && entry.loaded {
+                dirty.insert(*entry.key());
+            }
+        }
+        let world = VoxelWorld {
+            chunks,
+            dirty: Arc::new(Mutex::new(dirty)),
+            heights: Arc::new(DashMap::new()),
+            biomes: Arc::new(DashMap::new()),
+        };
+        let chunk_positions = world
+            .chunks
+            .iter()
+            .map(|entry| *entry.key())
+            .collect::<Vec<_>>();
+        for chunk_pos in chunk_positions {
+            world.merge_chunk_heights(chunk_pos);
+        }
+        world
     }
 
     pub fn load_chunk(&self, chunk_pos: ChunkPos, serialized_data: &[u8]) {
         let chunk = Chunk::deserialize(serialized_data);
-        let tracked_chunk = TrackedChunk {
-            chunk,
-            ao_image: None,
-            loaded: true,
-            meshing: false,
-            changed: true, // Mark as changed to ensure it gets meshed
-        };
+        // Marked as changed to ensure it gets meshed
+        let tracked_chunk = TrackedChunk::from_chunk(chunk);
 
         self.chunks.insert(chunk_pos, tracked_chunk);
+        self.merge_chunk_heights(chunk_pos);
+        self.dirty.lock().insert(chunk_pos);
+    }
+
+    /// The chunk as it is now, it can be read for as long as needed without blocking edits
+    pub fn snapshot(&self, chunk_pos: ChunkPos) -> Option<ChunkSnapshot> {
+        self.chunks.get(&chunk_pos).map(|chunk| chunk.snapshot())
     }
 
     pub fn save_chunk(&self, chunk_pos: ChunkPos) -> Option<Vec<u8>> {
-        if let Some(chunk) = self.chunks.get(&chunk_pos) {
-            Some(chunk.chunk.serialize())
-        } else {
-            None
-        }
+        // serialized from a snapshot so the chunk isn't locked meanwhile
+        Some(self.snapshot(chunk_pos)?.serialize())
     }
 
     pub fn set_block(&self, pos: BlockPos, block: Block, from_place: bool) {
@@ -109,6 +207,7 @@ impl VoxelWorld {
             new_chunk.set(chunked_pos, block);
             self.chunks.insert(chunk_pos, new_chunk);
         }
+        self.update_height(pos, block);
         if from_place {
             self.mark_change(chunk_pos, chunked_pos, block);
         }
@@ -123,6 +222,7 @@ impl VoxelWorld {
             .entry(chunk_pos)
             .or_insert_with(TrackedChunk::new)
             .set(chunked_pos, block);
+        self.update_height(pos, block);
         self.mark_change(chunk_pos, chunked_pos, block);
         true
     }
@@ -131,10 +231,11 @@ impl VoxelWorld {
         if let Some(mut chunk) = self.chunks.get_mut(&chunk_pos) {
             chunk.loaded = true;
             chunk.changed = true;
-            true
         } else {
-            false // Chunk doesn't exist, so we can't mark it as loaded
+            return false; // Chunk doesn't exist, so we can't mark it as loaded
         }
+        self.dirty.lock().insert(chunk_pos);
+        true
     }
 
     pub fn set_yrange(
@@ -167,15 +268,38 @@ impl VoxelWorld {
         }
     }
 
-    pub fn set_if_empty(&self, pos: BlockPos, block: Block) {
-        let (chunk_pos, chunked_pos) = <(ChunkPos, ChunkedPos)>::from(pos);
-        if self
-            .chunks
-            .entry(chunk_pos)
-            .or_insert_with(TrackedChunk::new)
-            .set_if_empty(chunked_pos, block)
-        {
-            self.mark_change(chunk_pos, chunked_pos, block);
+    /// Writes vertical runs of blocks (x, z, bottom, top inclusive, block) in a column,
+    /// locking each chunk only once. Meant for generation, doesn't mark anything as changed.
+    pub fn set_col_runs(&self, col_pos: ColPos, runs: &[(ColedPos, i32, i32, Block)]) {
+        for cy in 0..Y_CHUNKS as i32 {
+            let (chunk_bottom, chunk_top) = (cy * CHUNK_S1I, cy * CHUNK_S1I + CHUNK_S1I - 1);
+            let mut chunk = None;
+            for &((x, z), bottom, top, block) in runs {
+                let (bottom, top) = (bottom.max(chunk_bottom), top.min(chunk_top));
+                if bottom > top {
+                    continue;
+                }
+                let chunk = chunk.get_or_insert_with(|| {
+                    self.chunks
+                        .entry(ChunkPos {
+                            x: col_pos.x,
+                            y: cy,
+                            z: col_pos.z,
+                        })
+                        .or_insert_with(TrackedChunk::new)
+                });
+                let local_top = (top - chunk_bottom) as usize;
+                chunk.set_yrange((x, local_top, z), (top - bottom) as usize, block);
+            }
+        }
+        let mut heights = self
+            .heights
+            .entry(col_pos)
+            .or_insert_with(ColumnHeights::new);
+        for &(pos2d, _, top, block) in runs {
+            if block != Block::Air {
+                heights.merge(pos2d, Some(top), block.is_opaque().then_some(top));
+            }
         }
     }
 
@@ -195,22 +319,114 @@ impl VoxelWorld {
         }
     }
 
+    /// Checks the occupancy masks rather than the palette, cheap enough for collision checks.
+    /// Fluids don't count, agents go through them.
+    pub fn is_solid(&self, pos: BlockPos) -> bool {
+        if pos.y < 0 {
+            return false;
+        }
+        let (chunk_pos, (x, y, z)) = <(ChunkPos, ChunkedPos)>::from(pos);
+        match self.chunks.get(&chunk_pos) {
+            None => false,
+            Some(chunk) => chunk.occupancy.is_collidable((x + 1, y + 1, z + 1)),
+        }
+    }
+
     pub fn top_block(&self, pos: BlockPos2d) -> (Block, i32) {
+        match self.surface_height(pos) {
+            Some(y) => (
+                self.get_block(BlockPos {
+                    x: pos.x,
+                    y,
+                    z: pos.z,
+                }),
+                y,
+            ),
+            None => (Block::Air, 0),
+        }
+    }
+
+    /// y of the highest non-air block at x,z
+    pub fn surface_height(&self, pos: BlockPos2d) -> Option<i32> {
+        let (col_pos, pos2d) = pos.into();
+        self.heights.get(&col_pos)?.top(pos2d)
+    }
+
+    /// y of the highest opaque block at x,z, ie. the one that receives sunlight/rain
+    pub fn opaque_height(&self, pos: BlockPos2d) -> Option<i32> {
         let (col_pos, pos2d) = pos.into();
+        self.heights.get(&col_pos)?.opaque_top(pos2d)
+    }
+
+    pub fn biome(&self, pos: BlockPos2d) -> Option<Biome> {
+        let (col_pos, (x, z)): (ColPos, ColedPos) = pos.into();
+        Some(self.biomes.get(&col_pos)?[z + x * CHUNK_S1])
+    }
+
+    fn update_height(&self, pos: BlockPos, block: Block) {
+        // the builder chunk lives above the world and doesn't count
+        if pos.y < 0 || pos.y >= MAX_HEIGHT as i32 {
+            return;
+        }
+        let (col_pos, (x, y, z)): (ColPos, (usize, i32, usize)) = pos.into();
+        let rescan = self
+            .heights
+            .entry(col_pos)
+            .or_insert_with(ColumnHeights::new)
+            .update((x, z), y, block);
+        if rescan {
+            let (top, opaque_top) = self.scan_heights(col_pos, (x, z));
+            if let Some(mut heights) = self.heights.get_mut(&col_pos) {
+                heights.set((x, z), top, opaque_top);
+            }
+        }
+    }
+
+    fn scan_heights(&self, col_pos: ColPos, pos2d: ColedPos) -> (Option<i32>, Option<i32>) {
+        let mut top = None;
         for y in (0..Y_CHUNKS as i32).rev() {
             let chunk_pos = ChunkPos {
                 x: col_pos.x,
                 y,
                 z: col_pos.z,
             };
-            if let Some(chunk) = self.chunks.get(&chunk_pos) {
-                let (block, block_y) = chunk.top(pos2d);
-                if *block != Block::Air {
-                    return (block.clone(), y * CHUNK_S1 as i32 + block_y as i32);
-                }
+            let Some(chunk) = self.chunks.get(&chunk_pos) else {
+                continue;
+            };
+            let (chunk_top, chunk_opaque_top) = chunk.tops(pos2d);
+            let offset = y * CHUNK_S1I;
+            if top.is_none() {
+                top = chunk_top.map(|dy| offset + dy as i32);
+            }
+            if let Some(dy) = chunk_opaque_top {
+                return (top, Some(offset + dy as i32));
+            }
+        }
+        (top, None)
+    }
+
+    fn merge_chunk_heights(&self, chunk_pos: ChunkPos) {
+        if chunk_pos.y < 0 || chunk_pos.y >= Y_CHUNKS as i32 {
+            return;
+        }
+        let Some(chunk) = self.chunks.get(&chunk_pos) else {
+            return;
+        };
+        let offset = chunk_pos.y * CHUNK_S1I;
+        let mut heights = self
+            .heights
+            .entry(chunk_pos.into())
+            .or_insert_with(ColumnHeights::new);
+        for x in 0..CHUNK_S1 {
+            for z in 0..CHUNK_S1 {
+                let (top, opaque_top) = chunk.tops((x, z));
+                heights.merge(
+                    (x, z),
+                    top.map(|dy| offset + dy as i32),
+                    opaque_top.map(|dy| offset + dy as i32),
+                );
             }
         }
-        (Block::Air, 0)
     }
 
     pub fn is_col_loaded(&self, player_pos: Vec3) -> bool {
@@ -235,7 +451,31 @@ impl VoxelWorld {
         }
     }
 
+    /// Moves a column generated in another world into this one, and queues its chunks for meshing
+    pub fn insert_col(&self, col_pos: ColPos, from: &VoxelWorld) {
+        let mut inserted = Vec::new();
+        for chunk_pos in chunks_in_col(&col_pos) {
+            if let Some((_, chunk)) = from.chunks.remove(&chunk_pos) {
+                if chunk.loaded {
+                    inserted.push(chunk_pos);
+                }
+                self.chunks.insert(chunk_pos, chunk);
+            }
+        }
+        if let Some((_, heights)) = from.heights.remove(&col_pos) {
+            self.heights.insert(col_pos, heights);
+        }
+        if let Some((_, biomes)) = from.biomes.remove(&col_pos) {
+            self.biomes.insert(col_pos, biomes);
+        }
+        let mut dirty = self.dirty.lock();
+        for chunk_pos in inserted {
+            dirty.insert(chunk_pos);
+        }
+    }
+
     pub fn unload_col(&self, col: ColPos) {
+        let mut dirty = self.dirty.lock();
         for y in 0..Y_CHUNKS as i32 {
             let chunk_pos = ChunkPos {
                 x: col.x,
@@ -243,17 +483,48 @@ impl VoxelWorld {
                 z: col.z,
             };
             self.chunks.remove(&chunk_pos);
+            dirty.remove(&chunk_pos);
         }
+        self.heights.remove(&col);
+        self.biomes.remove(&col);
     }
 
     pub fn mark_change_single(&self, chunk_pos: ChunkPos) {
-        if let Some(mut chunk) = self.chunks.get_mut(&chunk_pos) {
+        let loaded = if let Some(mut chunk) = self.chunks.get_mut(&chunk_pos) {
             chunk.changed = true;
+            chunk.loaded
         } else {
             println!("couldn't get_mut chunk {:?}", chunk_pos);
+            return;
+        };
+        // unloaded chunks will be queued by set_loaded
+        if loaded {
+            self.dirty.lock().insert(chunk_pos);
         }
     }
 
+    /// Takes the most urgent changed chunk that isn't already being meshed, and flags it as meshing
+    pub fn pop_closest_change(&self) -> Option<ChunkPos> {
+        let span =
+            info_span!("selecting chunk to mesh", name = "selecting chunk to mesh").entered();
+        let mut dirty = self.dirty.lock();
+        let res = loop {
+            let chunk_pos = dirty.pop()?;
+            match self.chunks.get(&chunk_pos) {
+                Some(chunk) if chunk.changed && chunk.loaded => break chunk_pos,
+                // stale, nothing to mesh
+                _ => dirty.done_meshing(chunk_pos),
+            }
+        };
+        span.exit();
+        Some(res)
+    }
+
+    /// Must be called once the meshing of a chunk from `pop_closest_change` is over
+    pub fn done_meshing(&self, chunk_pos: ChunkPos) {
+        self.dirty.lock().done_meshing(chunk_pos);
+    }
+
     fn border_sign(coord: usize) -> i32 {
         if coord == 0 {
             -1
@@ -424,67 +695,84 @@ impl VoxelWorld {
         self.mark_change_single(neighbor_chunk_pos);
     }
 
+    /// Casts a ray in world space and returns the first targetable block hit within `dist` world units
     pub fn raycast(&self, start: Vec3, dir: Vec3, dist: f32) -> Option<BlockRayCastHit> {
-        let mut pos = BlockPos {
-            x: start.x.floor() as i32,
-            y: start.y.floor() as i32,
-            z: start.z.floor() as i32,
-        };
-        let mut last_pos;
-        let sx = dir.x.signum() as i32;
-        let sy = dir.y.signum() as i32;
-        let sz = dir.z.signum() as i32;
-        if sx == 0 && sy == 0 && sz == 0 {
+        self.raycast_filtered(start, dir, dist, |block| block.is_targetable())
+    }
+
+    /// Voxel DDA from `start` along `dir` (both in world space), returns the first block accepted by `filter`.
+    /// The block containing `start` is ignored.
+    pub fn raycast_filtered(
+        &self,
+        start: Vec3,
+        dir: Vec3,
+        dist: f32,
+        filter: impl Fn(Block) -> bool,
+    ) -> Option<BlockRayCastHit> {
+        let dir = dir.normalize_or_zero();
+        if dir == Vec3::ZERO {
             return None;
         }
-        let next_x = (pos.x + sx.max(0)) as f32;
-        let next_y = (pos.y + sy.max(0)) as f32;
-        let next_z = (pos.z + sz.max(0)) as f32;
-        let mut t_max_x = (next_x - start.x) / dir.x;
-        let mut t_max_y = (next_y - start.y) / dir.y;
-        let mut t_max_z = (next_z - start.z) / dir.z;
-        let slope_x = 1. / dir.x.abs();
-        let slope_y = 1. / dir.y.abs();
-        let slope_z = 1. / dir.z.abs();
+        // work in voxel space, where voxels are unit cubes
+        let origin = start * VOXELS_PER_UNIT;
+        let max_t = dist * VOXELS_PER_UNIT;
+        let mut pos = [
+            origin.x.floor() as i32,
+            origin.y.floor() as i32,
+            origin.z.floor() as i32,
+        ];
+        let mut step = [0; 3];
+        let mut t_max = Vec3::INFINITY;
+        let mut t_delta = Vec3::INFINITY;
+        for axis in 0..3 {
+            if dir[axis] > 0. {
+                step[axis] = 1;
+                t_max[axis] = (pos[axis] as f32 + 1. - origin[axis]) / dir[axis];
+                t_delta[axis] = 1. / dir[axis];
+            } else if dir[axis] < 0. {
+                step[axis] = -1;
+                t_max[axis] = (pos[axis] as f32 - origin[axis]) / dir[axis];
+                t_delta[axis] = -1. / dir[axis];
+            }
+        }
         loop {
-            last_pos = pos;
-            if t_max_x < t_max_y {
-                if t_max_x < t_max_z {
-                    if t_max_x >= dist {
-                        return None;
-                    };
-                    pos.x += sx;
-                    t_max_x += slope_x;
+            let axis = if t_max.x < t_max.y {
+                if t_max.x < t_max.z {
+                    0
                 } else {
-                    if t_max_z >= dist {
-                        return None;
-                    };
-                    pos.z += sz;
-                    t_max_z += slope_z;
+                    2
                 }
-            } else if t_max_y < t_max_z {
-                if t_max_y >= dist {
-                    return None;
-                };
-                pos.y += sy;
-                t_max_y += slope_y;
+            } else if t_max.y < t_max.z {
+                1
             } else {
-                if t_max_z >= dist {
-                    return None;
-                };
-                pos.z += sz;
-                t_max_z += slope_z;
+                2
+            };
+            let t = t_max[axis];
+            if t > max_t {
+                return None;
             }
-            if self.get_block_safe(pos).is_targetable() {
-                return Some(BlockRayCastHit {
-                    pos,
-                    normal: Vec3 {
-                        x: (last_pos.x - pos.x) as f32,
-                        y: (last_pos.y - pos.y) as f32,
-                        z: (last_pos.z - pos.z) as f32,
-                    },
-                });
+            pos[axis] += step[axis];
+            t_max[axis] += t_delta[axis];
+            let block_pos = BlockPos::new(pos[0], pos[1], pos[2]);
+            if !filter(self.get_block_safe(block_pos)) {
+                continue;
             }
+            // we entered the block through the face opposite to the step direction
+            let face = match (axis, step[axis]) {
+                (0, 1) => Face::Left,
+                (0, _) => Face::Right,
+                (1, 1) => Face::Down,
+                (1, _) => Face::Up,
+                (2, 1) => Face::Back,
+                _ => Face::Front,
+            };
+            let [nx, ny, nz] = face.n();
+            return Some(BlockRayCastHit {
+                pos: block_pos,
+                face,
+                distance: t / VOXELS_PER_UNIT,
+                place_pos: block_pos + (nx, ny, nz),
+            });
         }
     }
 }